@@ -0,0 +1,682 @@
+use reqwest::blocking::Client;
+use serde::Serialize;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const GEMINI_API_URL: &str = "https://generativelanguage.googleapis.com";
+const DEFAULT_GEMINI_API_VERSION: &str = "v1beta";
+const ZAI_API_URL: &str = "https://api.z.ai/api/coding/paas/v4/chat/completions";
+const OPENAI_API_URL: &str = "https://api.openai.com/v1/chat/completions";
+const DEFAULT_OLLAMA_HOST: &str = "http://localhost:11434";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GenerationOptions {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+/// Translates a natural-language prompt into a shell command.
+///
+/// ```
+/// use httpmock::prelude::*;
+/// use nlsh::{LlmProvider, OpenAiProvider};
+///
+/// let server = MockServer::start();
+/// server.mock(|when, then| {
+///     when.method(POST)
+///         .path("/v1/chat/completions")
+///         .header("authorization", "Bearer test-key");
+///     then.status(200).json_body(serde_json::json!({
+///         "choices": [{ "message": { "content": "ls -la" } }],
+///         "usage": { "total_tokens": 12 }
+///     }));
+/// });
+///
+/// let provider = OpenAiProvider::new("gpt-4o-mini")
+///     .with_client(reqwest::blocking::Client::new())
+///     .with_base_url(server.url("/v1/chat/completions"));
+/// assert_eq!(provider.translate("list files", "test-key").unwrap(), "ls -la");
+/// assert_eq!(provider.take_token_usage(), 12);
+/// assert_eq!(provider.take_token_usage(), 0);
+/// ```
+pub trait LlmProvider {
+    fn translate(&self, prompt: &str, api_key: &str) -> Result<String, String>;
+
+    /// Returns the tokens reported since the last call and resets the count.
+    fn take_token_usage(&self) -> u64 {
+        0
+    }
+}
+
+/// Settings shared by every provider client.
+struct ProviderConfig {
+    model: String,
+    options: GenerationOptions,
+    client: Option<Client>,
+    base_url: Option<String>,
+    tokens_used: AtomicU64,
+}
+
+impl ProviderConfig {
+    fn new(model: String) -> Self {
+        Self {
+            model,
+            options: GenerationOptions::default(),
+            client: None,
+            base_url: None,
+            tokens_used: AtomicU64::new(0),
+        }
+    }
+
+    fn client(&self) -> Result<Client, String> {
+        match &self.client {
+            Some(client) => Ok(client.clone()),
+            None => http_client(),
+        }
+    }
+
+    fn url(&self, default: impl FnOnce() -> String) -> String {
+        self.base_url.clone().unwrap_or_else(default)
+    }
+
+    fn record_usage(&self, tokens: Option<u64>) {
+        if let Some(tokens) = tokens {
+            self.tokens_used.fetch_add(tokens, Ordering::Relaxed);
+        }
+    }
+
+    fn take_usage(&self) -> u64 {
+        self.tokens_used.swap(0, Ordering::Relaxed)
+    }
+}
+
+macro_rules! provider_builder {
+    ($provider:ident) => {
+        impl $provider {
+            pub fn new(model: impl Into<String>) -> Self {
+                Self {
+                    config: ProviderConfig::new(model.into()),
+                }
+            }
+
+            pub fn with_options(mut self, options: GenerationOptions) -> Self {
+                self.config.options = options;
+                self
+            }
+
+            pub fn with_client(mut self, client: Client) -> Self {
+                self.config.client = Some(client);
+                self
+            }
+
+            pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+                self.config.base_url = Some(base_url.into());
+                self
+            }
+        }
+    };
+}
+
+pub struct GeminiProvider {
+    config: ProviderConfig,
+}
+
+provider_builder!(GeminiProvider);
+
+impl LlmProvider for GeminiProvider {
+    fn translate(&self, prompt: &str, api_key: &str) -> Result<String, String> {
+        let base_url = self
+            .config
+            .url(|| env_url("NLSH_GEMINI_URL", GEMINI_API_URL));
+        gemini_request(&self.config, &base_url, prompt, api_key)
+    }
+
+    fn take_token_usage(&self) -> u64 {
+        self.config.take_usage()
+    }
+}
+
+pub struct ZaiProvider {
+    config: ProviderConfig,
+}
+
+provider_builder!(ZaiProvider);
+
+impl LlmProvider for ZaiProvider {
+    fn translate(&self, prompt: &str, api_key: &str) -> Result<String, String> {
+        let url = self.config.url(|| env_url("NLSH_ZAI_URL", ZAI_API_URL));
+        zai_request(&self.config, &url, prompt, api_key)
+    }
+
+    fn take_token_usage(&self) -> u64 {
+        self.config.take_usage()
+    }
+}
+
+pub struct OpenAiProvider {
+    config: ProviderConfig,
+}
+
+provider_builder!(OpenAiProvider);
+
+impl LlmProvider for OpenAiProvider {
+    fn translate(&self, prompt: &str, api_key: &str) -> Result<String, String> {
+        let url = self
+            .config
+            .url(|| env_url("NLSH_OPENAI_URL", OPENAI_API_URL));
+        openai_request(&self.config, &url, prompt, api_key)
+    }
+
+    fn take_token_usage(&self) -> u64 {
+        self.config.take_usage()
+    }
+}
+
+pub struct OllamaProvider {
+    config: ProviderConfig,
+}
+
+provider_builder!(OllamaProvider);
+
+impl LlmProvider for OllamaProvider {
+    fn translate(&self, prompt: &str, _api_key: &str) -> Result<String, String> {
+        let host = self.config.url(ollama_host);
+        ollama_request(&self.config, &host, prompt)
+    }
+}
+
+#[derive(Serialize)]
+struct GeminiRequest {
+    contents: Vec<GeminiContent>,
+    #[serde(rename = "generationConfig", skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GeminiGenerationConfig>,
+}
+
+#[derive(Serialize)]
+struct GeminiGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(rename = "maxOutputTokens", skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct GeminiContent {
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Serialize)]
+struct GeminiPart {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Serialize)]
+struct OllamaRequest {
+    model: String,
+    prompt: String,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<OllamaOptions>,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+}
+
+fn describe_send_error(err: reqwest::Error) -> String {
+    let host = err
+        .url()
+        .and_then(|url| url.host_str())
+        .unwrap_or("the provider")
+        .to_string();
+    let message = if err.is_timeout() {
+        format!("network unreachable: request to {} timed out", host)
+    } else if err.is_connect() {
+        format!("network unreachable: could not connect to {}", host)
+    } else {
+        return err.without_url().to_string();
+    };
+
+    let ollama_configured =
+        env::var_os("OLLAMA_HOST").is_some() || env::var_os("OLLAMA_MODEL").is_some();
+    if ollama_configured && !ollama_host().contains(&host) {
+        format!(
+            "{} (a local model is configured; try `--provider ollama`)",
+            message
+        )
+    } else {
+        message
+    }
+}
+
+fn env_number<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+fn http_client() -> Result<Client, String> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client.clone());
+    }
+    let timeout = env_number("NLSH_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS);
+    let client = Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()
+        .map_err(|err| err.to_string())?;
+    Ok(CLIENT.get_or_init(|| client).clone())
+}
+
+fn send_with_retry(
+    build: impl Fn() -> reqwest::blocking::RequestBuilder,
+) -> Result<reqwest::blocking::Response, String> {
+    let max_retries: u32 = env_number("NLSH_MAX_RETRIES", DEFAULT_MAX_RETRIES);
+    let mut attempt = 0;
+    loop {
+        match build().send() {
+            Ok(response) if response.status().is_server_error() && attempt < max_retries => {}
+            Ok(response) if !response.status().is_success() => {
+                let status = response.status();
+                let body = response.text().unwrap_or_default();
+                return Err(format!(
+                    "API error (HTTP {}): {}",
+                    status,
+                    api_error_message(&body)
+                ));
+            }
+            Ok(response) => return Ok(response),
+            Err(err) if err.is_connect() && attempt < max_retries => {}
+            Err(err) => return Err(describe_send_error(err)),
+        }
+        let backoff = 500u64.saturating_mul(2u64.saturating_pow(attempt));
+        std::thread::sleep(Duration::from_millis(backoff));
+        attempt += 1;
+    }
+}
+
+fn parse_json_body(response: reqwest::blocking::Response) -> Result<serde_json::Value, String> {
+    let body = response.text().map_err(|err| err.to_string())?;
+    serde_json::from_str(&body).map_err(|err| {
+        let excerpt: String = body.trim().chars().take(300).collect();
+        format!("invalid JSON in response ({}): {}", err, excerpt)
+    })
+}
+
+fn api_error_message(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| error_message(&value))
+        .unwrap_or_else(|| body.trim().chars().take(300).collect())
+}
+
+fn error_message(value: &serde_json::Value) -> Option<String> {
+    let error = value.get("error")?;
+    let message = error
+        .get("message")
+        .and_then(|m| m.as_str())
+        .or_else(|| error.as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| error.to_string());
+    Some(message)
+}
+
+fn ollama_host() -> String {
+    let host = env::var("OLLAMA_HOST")
+        .ok()
+        .filter(|host| !host.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_OLLAMA_HOST.to_string());
+    let host = host.trim().trim_end_matches('/');
+    if host.contains("://") {
+        host.to_string()
+    } else {
+        format!("http://{}", host)
+    }
+}
+
+fn ollama_request(config: &ProviderConfig, host: &str, prompt: &str) -> Result<String, String> {
+    let client = config.client()?;
+    let options = config.options;
+    let request = OllamaRequest {
+        model: config.model.clone(),
+        prompt: prompt.to_string(),
+        stream: false,
+        options: (options.temperature.is_some() || options.max_tokens.is_some()).then_some(
+            OllamaOptions {
+                temperature: options.temperature,
+                num_predict: options.max_tokens,
+            },
+        ),
+    };
+
+    let url = format!("{}/api/generate", host.trim_end_matches('/'));
+    let response = send_with_retry(|| client.post(&url).json(&request))?;
+    let status = response.status();
+    let value = parse_json_body(response)?;
+
+    let text = value
+        .get("response")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| format!("Ollama response missing content (status: {})", status))?;
+
+    Ok(text.trim().to_string())
+}
+
+fn env_url(key: &str, default: &str) -> String {
+    env::var(key)
+        .ok()
+        .map(|value| value.trim().trim_end_matches('/').to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+fn gemini_api_version() -> String {
+    env::var("NLSH_GEMINI_API_VERSION")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| DEFAULT_GEMINI_API_VERSION.to_string())
+}
+
+fn gemini_request(
+    config: &ProviderConfig,
+    base_url: &str,
+    prompt: &str,
+    api_key: &str,
+) -> Result<String, String> {
+    let client = config.client()?;
+    let options = config.options;
+    let request = GeminiRequest {
+        contents: vec![GeminiContent {
+            parts: vec![GeminiPart {
+                text: prompt.to_string(),
+            }],
+        }],
+        generation_config: (options.temperature.is_some() || options.max_tokens.is_some())
+            .then_some(GeminiGenerationConfig {
+                temperature: options.temperature,
+                max_output_tokens: options.max_tokens,
+            }),
+    };
+
+    let (base, query) = match base_url.split_once('?') {
+        Some((base, query)) => (base.trim_end_matches('/'), format!("{}&", query)),
+        None => (base_url.trim_end_matches('/'), String::new()),
+    };
+    let url = format!(
+        "{}/{}/models/{}:generateContent?{}key={}",
+        base,
+        gemini_api_version(),
+        config.model,
+        query,
+        api_key
+    );
+    let response = send_with_retry(|| client.post(&url).json(&request))?;
+
+    let value = parse_json_body(response)?;
+    if let Some(message) = error_message(&value) {
+        return Err(format!("Gemini API error: {}", message));
+    }
+    config.record_usage(
+        value
+            .pointer("/usageMetadata/totalTokenCount")
+            .and_then(|t| t.as_u64()),
+    );
+    let text = value
+        .get("candidates")
+        .and_then(|c| c.get(0))
+        .and_then(|c| c.get("content"))
+        .and_then(|c| c.get("parts"))
+        .and_then(|p| p.get(0))
+        .and_then(|p| p.get("text"))
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| "Gemini response missing content".to_string())?;
+
+    Ok(text.trim().to_string())
+}
+
+fn chat_request(
+    config: &ProviderConfig,
+    url: &str,
+    prompt: &str,
+    api_key: &str,
+) -> Result<(reqwest::StatusCode, serde_json::Value), String> {
+    let client = config.client()?;
+    let options = config.options;
+    let request = ChatRequest {
+        model: config.model.clone(),
+        messages: vec![ChatMessage {
+            role: "user".to_string(),
+            content: prompt.to_string(),
+        }],
+        temperature: options.temperature,
+        max_tokens: options.max_tokens,
+    };
+
+    let response = send_with_retry(|| client.post(url).bearer_auth(api_key).json(&request))?;
+    let status = response.status();
+    let value = parse_json_body(response)?;
+    if let Some(message) = error_message(&value) {
+        return Err(format!("API error (HTTP {}): {}", status, message));
+    }
+    config.record_usage(
+        value
+            .pointer("/usage/total_tokens")
+            .and_then(|t| t.as_u64()),
+    );
+
+    Ok((status, value))
+}
+
+fn zai_request(
+    config: &ProviderConfig,
+    url: &str,
+    prompt: &str,
+    api_key: &str,
+) -> Result<String, String> {
+    let (status, value) = chat_request(config, url, prompt, api_key)?;
+    let text = value
+        .get("choices")
+        .and_then(|c| c.get(0))
+        .and_then(extract_glm_command)
+        .ok_or_else(|| format!("z.ai response missing content (status: {})", status))?;
+
+    Ok(text)
+}
+
+fn openai_request(
+    config: &ProviderConfig,
+    url: &str,
+    prompt: &str,
+    api_key: &str,
+) -> Result<String, String> {
+    let (status, value) = chat_request(config, url, prompt, api_key)?;
+    let text = value
+        .pointer("/choices/0/message/content")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| format!("OpenAI response missing content (status: {})", status))?;
+
+    Ok(text.trim().to_string())
+}
+
+fn extract_glm_command(choice: &serde_json::Value) -> Option<String> {
+    let message = choice.get("message");
+    let content = message
+        .and_then(|m| m.get("content"))
+        .and_then(|t| t.as_str())
+        .or_else(|| choice.get("text").and_then(|t| t.as_str()))
+        .or_else(|| choice.get("content").and_then(|t| t.as_str()))?;
+
+    let has_reasoning_field = message
+        .and_then(|m| m.get("reasoning_content"))
+        .and_then(|r| r.as_str())
+        .is_some_and(|r| !r.trim().is_empty());
+    if has_reasoning_field {
+        return Some(content.trim().to_string());
+    }
+
+    let content = match content.rsplit_once("</think>") {
+        Some((_, after)) => after,
+        None => content,
+    };
+    let lines: Vec<&str> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    if lines.len() < 2 || !lines.iter().any(|line| looks_like_prose(line)) {
+        return Some(content.trim().to_string());
+    }
+
+    let command = lines
+        .iter()
+        .rev()
+        .find(|line| !looks_like_prose(line) && !line.starts_with("```"))
+        .map(|line| line.to_string())
+        .unwrap_or_else(|| content.trim().to_string());
+    Some(command)
+}
+
+fn looks_like_prose(line: &str) -> bool {
+    let starts_upper = line.chars().next().is_some_and(char::is_uppercase);
+    starts_upper && (line.ends_with('.') || line.ends_with(':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use serde_json::json;
+
+    #[test]
+    fn glm_command_ignores_reasoning_content_field() {
+        let choice = json!({
+            "message": {
+                "reasoning_content": "The user wants to list files. I should use ls.",
+                "content": "ls -la\n"
+            }
+        });
+        assert_eq!(extract_glm_command(&choice).as_deref(), Some("ls -la"));
+    }
+
+    #[test]
+    fn glm_command_follows_inline_reasoning() {
+        let choice = json!({
+            "message": {
+                "content": "The user wants to see hidden files.\nI will use ls with -a.\nls -la"
+            }
+        });
+        assert_eq!(extract_glm_command(&choice).as_deref(), Some("ls -la"));
+
+        let choice = json!({
+            "message": { "content": "<think>Use du and sort.</think>\ndu -sh * | sort -h" }
+        });
+        assert_eq!(
+            extract_glm_command(&choice).as_deref(),
+            Some("du -sh * | sort -h")
+        );
+    }
+
+    #[test]
+    fn glm_command_falls_back_to_text_and_content() {
+        let choice = json!({ "text": "ls -la" });
+        assert_eq!(extract_glm_command(&choice).as_deref(), Some("ls -la"));
+
+        let choice = json!({ "content": " pwd\n" });
+        assert_eq!(extract_glm_command(&choice).as_deref(), Some("pwd"));
+
+        assert_eq!(extract_glm_command(&json!({ "index": 0 })), None);
+    }
+
+    #[test]
+    fn gemini_reads_the_first_candidate_part() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/v1beta/models/gemini-test:generateContent")
+                .query_param("key", "test-key");
+            then.status(200).json_body(json!({
+                "candidates": [
+                    { "content": { "parts": [{ "text": " ls -la\n" }, { "text": "pwd" }] } },
+                    { "content": { "parts": [{ "text": "ls" }] } }
+                ],
+                "usageMetadata": { "totalTokenCount": 42 }
+            }));
+        });
+
+        let provider = GeminiProvider::new("gemini-test").with_base_url(server.base_url());
+        assert_eq!(
+            provider.translate("list files", "test-key").unwrap(),
+            "ls -la"
+        );
+        assert_eq!(provider.take_token_usage(), 42);
+        mock.assert();
+    }
+
+    #[test]
+    fn zai_reads_the_first_choice_message() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST)
+                .path("/chat/completions")
+                .header("authorization", "Bearer test-key")
+                .json_body_partial(r#"{ "model": "glm-test" }"#);
+            then.status(200).json_body(json!({
+                "choices": [
+                    { "message": { "content": "df -h" } },
+                    { "message": { "content": "du -sh" } }
+                ]
+            }));
+        });
+
+        let provider = ZaiProvider::new("glm-test").with_base_url(server.url("/chat/completions"));
+        assert_eq!(
+            provider.translate("disk usage", "test-key").unwrap(),
+            "df -h"
+        );
+    }
+
+    #[test]
+    fn malformed_json_reports_the_body() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST);
+            then.status(200).body("<html>gateway timeout</html>");
+        });
+
+        let gemini = GeminiProvider::new("gemini-test").with_base_url(server.base_url());
+        let err = gemini.translate("list files", "test-key").unwrap_err();
+        assert!(err.starts_with("invalid JSON in response"), "{}", err);
+        assert!(err.contains("<html>gateway timeout</html>"), "{}", err);
+
+        let zai = ZaiProvider::new("glm-test").with_base_url(server.url("/chat/completions"));
+        let err = zai.translate("list files", "test-key").unwrap_err();
+        assert!(err.contains("<html>gateway timeout</html>"), "{}", err);
+    }
+}
